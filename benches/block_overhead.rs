@@ -0,0 +1,34 @@
+//! Measures what a `block!()` costs on top of the code it wraps: run with
+//! `cargo bench`. Prints nanoseconds per block rather than asserting a
+//! limit, since that depends on the machine.
+
+use std::hint::black_box;
+use std::time::Instant;
+
+const ITERATIONS: u32 = 1_000_000;
+
+fn bare(i: u32) -> u32 {
+    black_box(i)
+}
+
+fn profiled(i: u32) -> u32 {
+    let _b = pprof::block!();
+    black_box(i)
+}
+
+fn ns_per_call(f: fn(u32) -> u32) -> f64 {
+    let start = Instant::now();
+    for i in 0..ITERATIONS {
+        f(i);
+    }
+    start.elapsed().as_nanos() as f64 / ITERATIONS as f64
+}
+
+fn main() {
+    pprof::init();
+    // Registers the anchor and warms the caches.
+    ns_per_call(profiled);
+    let bare_ns = ns_per_call(bare);
+    let profiled_ns = ns_per_call(profiled);
+    println!("block!() overhead: {:.1}ns per block", profiled_ns - bare_ns);
+}
@@ -0,0 +1,37 @@
+//! Compares `iter_sorted_by_self_time` for 10 anchors, which sorts on the
+//! stack, with 100 anchors, which sorts a `Vec`: run with `cargo bench`.
+
+use pprof::Profiler;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const ITERATIONS: u32 = 100_000;
+
+fn profiler_with(anchors: usize) -> Profiler {
+    let mut p = Profiler::new();
+    for i in 0..anchors {
+        let id = p.get_anchor_id(&format!("anchor{}", i));
+        // Self times in no particular order, so the sort has work to do.
+        p.record_call(id, Duration::from_nanos((i as u64 * 7919) % 1000));
+    }
+    p
+}
+
+fn ns_per_iteration(p: &Profiler) -> f64 {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        for result in p.iter_sorted_by_self_time() {
+            black_box(result);
+        }
+    }
+    start.elapsed().as_nanos() as f64 / ITERATIONS as f64
+}
+
+fn main() {
+    for anchors in [10, 100] {
+        let p = profiler_with(anchors);
+        ns_per_iteration(&p);
+        let ns = ns_per_iteration(&p);
+        println!("{} anchors: {:.1}ns per iteration, {:.1}ns per anchor", anchors, ns, ns / anchors as f64);
+    }
+}
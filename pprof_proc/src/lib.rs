@@ -1,13 +1,84 @@
-use proc_macro::TokenStream;
+use proc_macro::{Delimiter, Spacing, TokenStream, TokenTree};
 
+// Splits `time!(<args> => { <body> })` into its block arguments and the
+// body. The arrow can't end a `block!` argument, so names that are
+// expressions ending in a brace (`if c { "a" } else { "b" }`) stay names.
+fn split_body(item: TokenStream) -> (TokenStream, Option<TokenTree>) {
+    let mut tokens: Vec<TokenTree> = item.into_iter().collect();
+    let is_body = match tokens.as_slice() {
+        [.., TokenTree::Punct(eq), TokenTree::Punct(gt), TokenTree::Group(g)] => {
+            eq.as_char() == '='
+                && eq.spacing() == Spacing::Joint
+                && gt.as_char() == '>'
+                && g.delimiter() == Delimiter::Brace
+        }
+        _ => false,
+    };
+    if !is_body {
+        return (tokens.into_iter().collect(), None);
+    }
+    let body = tokens.pop();
+    tokens.truncate(tokens.len() - 2);
+    (tokens.into_iter().collect(), body)
+}
+
+/// Expands to the guard returned by `pprof::block!(<args>)`; bind it to time
+/// the rest of the scope: `let _guard = time!("name");`. When the arguments
+/// are followed by `=> { ... }`, profiles just that body and evaluates to its
+/// value: `let n = time!("parse" => { parse(input) });`. Expands to `()` (or
+/// just the body) unless the `profile` feature is enabled.
 #[cfg(feature = "profile")]
 #[proc_macro]
 pub fn time(item: TokenStream) -> TokenStream {
-    format!("let _p = pprof::block!({});", item).parse().unwrap()
+    match split_body(item) {
+        (args, Some(body)) => format!("{{ let _p = pprof::block!({}); {} }}", args, body),
+        (args, None) => format!("pprof::block!({})", args),
+    }
+    .parse()
+    .unwrap()
 }
 
+/// Expands to the guard returned by `pprof::block!(<args>)`; bind it to time
+/// the rest of the scope: `let _guard = time!("name");`. When the arguments
+/// are followed by `=> { ... }`, profiles just that body and evaluates to its
+/// value: `let n = time!("parse" => { parse(input) });`. Expands to `()` (or
+/// just the body) unless the `profile` feature is enabled.
 #[cfg(not(feature = "profile"))]
 #[proc_macro]
-pub fn time(_item: TokenStream) -> TokenStream {
-    TokenStream::default()
+pub fn time(item: TokenStream) -> TokenStream {
+    match split_body(item) {
+        (_, Some(body)) => body.into(),
+        (_, None) => "()".parse().unwrap(),
+    }
+}
+
+// Whether `attr` is `#[skip_profile]` or `#[pprof_proc::skip_profile]`.
+fn is_skip_profile(attr: &syn::Attribute) -> bool {
+    attr.path().segments.last().is_some_and(|segment| segment.ident == "skip_profile")
+}
+
+/// Put on an `impl` block, times every method in it as if its body started
+/// with `let _guard = pprof::block!();`, so each method gets its own anchor.
+/// Methods marked `#[skip_profile]` are left alone. Only strips
+/// those markers unless the `profile` feature is enabled.
+#[proc_macro_attribute]
+pub fn profile_all(_attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut item = syn::parse_macro_input!(item as syn::ItemImpl);
+    for impl_item in &mut item.items {
+        let syn::ImplItem::Fn(method) = impl_item else {
+            continue;
+        };
+        let skip = method.attrs.iter().any(is_skip_profile);
+        method.attrs.retain(|attr| !is_skip_profile(attr));
+        if cfg!(feature = "profile") && !skip {
+            method.block.stmts.insert(0, syn::parse_quote! { let _pprof_guard = pprof::block!(); });
+        }
+    }
+    quote::quote!(#item).into()
+}
+
+/// Excludes a method from [`macro@profile_all`]. Does nothing on its own.
+#[proc_macro_attribute]
+pub fn skip_profile(_attr: TokenStream, item: TokenStream) -> TokenStream {
+    item
 }
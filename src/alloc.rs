@@ -0,0 +1,69 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+// Allocations made on this thread so far. Blocks snapshot these when they
+// start and end, the same way they read the timer.
+thread_local! {
+    static ALLOC_COUNT: Cell<u64> = const { Cell::new(0) };
+    static ALLOC_BYTES: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Wraps an allocator, counting every allocation made on each thread so
+/// blocks can report how much they allocated. Blocks only see allocations
+/// when the binary installs it as its global allocator:
+///
+/// ```
+/// use std::alloc::System;
+///
+/// #[global_allocator]
+/// static GLOBAL: pprof::CountingAllocator<System> = pprof::CountingAllocator::new(System);
+/// # fn main() {}
+/// ```
+pub struct CountingAllocator<A = System> {
+    inner: A,
+}
+
+impl<A> CountingAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        Self { inner }
+    }
+}
+
+// The crate's own tests check the counts.
+#[cfg(test)]
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator::new(System);
+
+// `try_with` since allocations can still happen while thread locals are
+// being torn down.
+fn count(bytes: usize) {
+    let _ = ALLOC_COUNT.try_with(|c| c.set(c.get() + 1));
+    let _ = ALLOC_BYTES.try_with(|c| c.set(c.get() + bytes as u64));
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for CountingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        self.inner.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        self.inner.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count(new_size);
+        self.inner.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout)
+    }
+}
+
+/// The number of allocations and allocated bytes on the current thread.
+#[inline(always)]
+pub(crate) fn allocations() -> (u64, u64) {
+    (ALLOC_COUNT.with(Cell::get), ALLOC_BYTES.with(Cell::get))
+}
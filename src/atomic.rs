@@ -0,0 +1,106 @@
+use crate::{Anchor, AnchorResult};
+use lazy_static::lazy_static;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+
+// The latest totals of one anchor of the global profiler, in nanoseconds.
+#[derive(Default)]
+struct AtomicAnchorStats {
+    exclusive_ns: AtomicU64,
+    inclusive_ns: AtomicU64,
+    calls: AtomicUsize,
+    bytes: AtomicUsize,
+}
+
+lazy_static! {
+    // Every anchor the global profiler has published, by name. Only taken
+    // for writing when a new anchor is published, so readers never wait for
+    // the blocks being profiled.
+    static ref PUBLISHED: RwLock<HashMap<String, Arc<AtomicAnchorStats>>> = RwLock::new(HashMap::new());
+}
+
+/// Where a profiler publishes its anchor totals for
+/// [`crate::Profiler::read_anchor_atomic`]. Only the global profiler does;
+/// clones and other profilers get a table that publishes nothing.
+#[derive(Default)]
+pub(crate) struct AtomicStatsTable {
+    enabled: bool,
+    // Published stats by anchor ID, so ending a block doesn't look up its
+    // name. Checked against the name, since anchors can be renamed.
+    cache: Vec<Option<(String, Arc<AtomicAnchorStats>)>>,
+}
+
+impl Clone for AtomicStatsTable {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl AtomicStatsTable {
+    pub(crate) fn global() -> Self {
+        Self { enabled: true, cache: Vec::new() }
+    }
+
+    // Stores the totals of the anchor `id`, with ticks at `freq` per second.
+    #[inline]
+    pub(crate) fn publish(&mut self, id: usize, anchor: &Anchor, freq: f64) {
+        if !self.enabled {
+            return;
+        }
+        if self.cache.len() <= id {
+            self.cache.resize(id + 1, None);
+        }
+        let cached = matches!(&self.cache[id], Some((name, _)) if *name == anchor.name);
+        if !cached {
+            let mut published = PUBLISHED.write().unwrap_or_else(|e| e.into_inner());
+            let stats = published.entry(anchor.name.to_string()).or_default();
+            self.cache[id] = Some((anchor.name.to_string(), Arc::clone(stats)));
+        }
+        let Some((_, stats)) = &self.cache[id] else {
+            return;
+        };
+        let to_ns = |ticks: u64| (ticks as f64 / freq * 1_000_000_000.0) as u64;
+        stats.exclusive_ns.store(to_ns(anchor.elapsed_exclusive), Ordering::Relaxed);
+        stats.inclusive_ns.store(to_ns(anchor.elapsed_inclusive), Ordering::Relaxed);
+        stats.calls.store(anchor.calls, Ordering::Relaxed);
+        stats.bytes.store(anchor.bytes, Ordering::Relaxed);
+    }
+
+    // Zeroes everything published, for a reset of the whole profiler.
+    pub(crate) fn clear(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        for stats in PUBLISHED.read().unwrap_or_else(|e| e.into_inner()).values() {
+            stats.exclusive_ns.store(0, Ordering::Relaxed);
+            stats.inclusive_ns.store(0, Ordering::Relaxed);
+            stats.calls.store(0, Ordering::Relaxed);
+            stats.bytes.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+// The published totals of the anchor named `name`. The fields are read one
+// by one, so a block ending meanwhile can make them disagree slightly.
+pub(crate) fn read(name: &str) -> Option<AnchorResult> {
+    let published = PUBLISHED.read().unwrap_or_else(|e| e.into_inner());
+    let stats = published.get(name)?;
+    let exclusive_ns = stats.exclusive_ns.load(Ordering::Relaxed);
+    let inclusive_ns = stats.inclusive_ns.load(Ordering::Relaxed);
+    Some(AnchorResult {
+        name: name.to_string(),
+        calls: stats.calls.load(Ordering::Relaxed),
+        bytes: stats.bytes.load(Ordering::Relaxed),
+        exclusive_ms: exclusive_ns as f64 / 1_000_000.0,
+        inclusive_ms: inclusive_ns as f64 / 1_000_000.0,
+        ticks_exclusive: exclusive_ns,
+        ticks_inclusive: inclusive_ns,
+        #[cfg(feature = "timeline")]
+        first_start_ns: 0,
+        #[cfg(feature = "timeline")]
+        last_end_ns: 0,
+        comment: None,
+        custom_metrics: BTreeMap::new(),
+    })
+}
@@ -0,0 +1,1734 @@
+use crate::{AnchorResult, FrozenProfiler, ProfileResults, Profiler};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Write as _};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+const BINARY_MAGIC: &[u8; 8] = b"PPROF\0\0\0";
+const BINARY_VERSION: u8 = 2;
+
+// The fixed columns of the CSV format, custom metrics follow.
+const CSV_COLUMNS: [&str; 5] = ["name", "calls", "bytes", "self_ms", "total_ms"];
+
+#[cfg(feature = "html")]
+const HTML_STYLE: &str = "body{font-family:sans-serif}table{border-collapse:collapse}\
+th,td{padding:2px 8px;text-align:right}td:nth-child(2){text-align:left}\
+th{cursor:pointer;background:#eee}tr.anchor{cursor:pointer}tr.anchor:hover{background:#f6f6f6}\
+.details td{text-align:left;background:#fafafa}.bar{height:10px;background:#d33;min-width:1px}\
+td:last-child{width:200px;text-align:left}";
+
+// Sorts the table by a column on a click of its heading (numeric columns by
+// their `data-v`), toggling between descending and ascending, and expands a
+// row's details on a click of the row. Every anchor is its own `tbody`, so
+// the details row moves along when sorting.
+#[cfg(feature = "html")]
+const HTML_SCRIPT: &str = "document.querySelectorAll('th').forEach((th,col)=>{th.onclick=()=>{\
+const table=th.closest('table');const desc=th.dataset.desc!=='1';th.dataset.desc=desc?'1':'0';\
+const key=b=>{const td=b.rows[0].cells[col];return td.dataset.v!==undefined?parseFloat(td.dataset.v):td.textContent};\
+Array.from(table.tBodies).sort((a,b)=>{const x=key(a),y=key(b);const c=x<y?-1:x>y?1:0;return desc?-c:c})\
+.forEach(b=>table.appendChild(b))}});\
+document.querySelectorAll('tr.anchor').forEach(tr=>{tr.onclick=()=>{\
+const d=tr.nextElementSibling;d.hidden=!d.hidden}});";
+
+// Name, type, help text and value of each per-anchor Prometheus series.
+type PrometheusMetric = (&'static str, &'static str, &'static str, fn(&AnchorResult) -> f64);
+
+const PROMETHEUS_METRICS: &[PrometheusMetric] = &[
+    ("pprof_anchor_calls_total", "counter", "Completed blocks per anchor.", |a| a.calls as f64),
+    ("pprof_anchor_bytes_total", "counter", "Bytes processed per anchor.", |a| a.bytes as f64),
+    ("pprof_anchor_self_seconds", "gauge", "Time spent in an anchor excluding children.", |a| {
+        a.exclusive_ms / 1000.0
+    }),
+    ("pprof_anchor_total_seconds", "gauge", "Time spent in an anchor including children.", |a| {
+        a.inclusive_ms / 1000.0
+    }),
+];
+
+impl Profiler {
+    /// Renders the profile as synthetic `perf script` output, one sample per
+    /// anchor with its exclusive time reported as the sample's cycle count.
+    /// The result can be fed to `stackcollapse-perf.pl | flamegraph.pl`.
+    pub fn report_perf_script(&mut self) -> String {
+        let freq = self.freq();
+        let pid = std::process::id();
+        let comm = std::env::current_exe()
+            .ok()
+            .and_then(|p| p.file_stem().map(|s| s.to_string_lossy().into_owned()))
+            .unwrap_or_else(|| "pprof".to_string());
+
+        let mut out = String::new();
+        let mut timestamp = 0;
+        for (id, anchor) in self.anchors.iter().enumerate().skip(1) {
+            if anchor.elapsed_exclusive == 0 {
+                continue;
+            }
+            timestamp += anchor.elapsed_exclusive;
+            let _ = writeln!(
+                out,
+                "{} {}/{} [000] {:.6}: {} cycles:",
+                comm,
+                pid,
+                pid,
+                timestamp as f64 / freq,
+                anchor.elapsed_exclusive,
+            );
+            let _ = writeln!(out, "\t{:>16x} {} ({})", id, anchor.name, comm);
+            out.push('\n');
+        }
+        out
+    }
+
+    /// Renders the call graph in the DOT language, e.g. for
+    /// `dot -Tsvg -o profile.svg`. Nodes are shaded by their share of the
+    /// total time spent exclusively in them.
+    pub fn report_graphviz(&mut self) -> String {
+        let total_duration = self.elapsed().as_nanos() as f64 / 1_000_000_000.0;
+        let freq = self.freq();
+
+        let mut out = String::new();
+        out.push_str("digraph pprof {\n");
+        out.push_str("    node [shape=box, style=filled];\n");
+        for (id, anchor) in self.anchors.iter().enumerate().skip(1) {
+            if anchor.calls == 0 {
+                continue;
+            }
+            let self_elapsed = anchor.elapsed_exclusive as f64 / freq;
+            let saturation = if total_duration > 0.0 { (self_elapsed / total_duration).clamp(0.0, 1.0) } else { 0.0 };
+            let _ = writeln!(
+                out,
+                "    n{} [label=\"{}\\ncalls: {}\\nself: {:.prec$}ms\", fillcolor=\"0.000 {:.3} 1.000\"];",
+                id,
+                escape_dot(&anchor.name),
+                anchor.calls,
+                self_elapsed * 1000.0,
+                saturation,
+                prec = self.precision,
+            );
+        }
+        // Only between drawn nodes: the root (which also collects blocks past
+        // `set_max_anchors`) and anchors without calls have none.
+        let drawn = |id: usize| id != 0 && self.anchors[id].calls != 0;
+        for (&(parent, child), edge) in &self.edges {
+            if !drawn(parent) || !drawn(child) {
+                continue;
+            }
+            let _ = writeln!(out, "    n{} -> n{} [label=\"{}\"];", parent, child, edge.calls);
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    /// Renders the report as a self-contained HTML page: a table with the
+    /// columns of [`Profiler::print`] that sorts by any column when its
+    /// heading is clicked, a bar per anchor showing its share of self time,
+    /// and rows that expand on click to list the anchor's callers and
+    /// callees. Everything is inlined, so the file can be opened anywhere.
+    #[cfg(feature = "html")]
+    pub fn report_html(&mut self) -> String {
+        let total_duration = self.elapsed().as_nanos() as f64 / 1_000_000_000.0;
+        let freq = self.freq();
+        let base = self.percentage_denominator(total_duration, freq);
+        let ranks = self.hotness_ranks();
+        let ms = |ticks: u64| ticks as f64 / freq * 1000.0;
+        let name = |id: usize| if id == 0 { "(top level)".to_string() } else { escape_html(&self.anchors[id].name) };
+
+        let mut rows = String::new();
+        for (id, anchor) in self.anchors.iter().enumerate().skip(1) {
+            if !self.is_shown(anchor, total_duration, freq) {
+                continue;
+            }
+            let (total_ms, self_ms) = (ms(anchor.elapsed_inclusive), ms(anchor.elapsed_exclusive));
+            let self_pct = if base == 0.0 { 0.0 } else { self_ms / 1000.0 / base * 100.0 };
+            let total_pct = if base == 0.0 { 0.0 } else { total_ms / 1000.0 / base * 100.0 };
+            let _ = write!(
+                rows,
+                "<tbody><tr class=\"anchor\"><td data-v=\"{rank}\">{rank_text}</td><td>{name}</td>\
+                 <td data-v=\"{calls}\">{calls}</td><td data-v=\"{total_ms}\">{total_ms:.prec$}</td>\
+                 <td data-v=\"{total_pct}\">{total_pct:.2}%</td><td data-v=\"{self_ms}\">{self_ms:.prec$}</td>\
+                 <td data-v=\"{self_pct}\">{self_pct:.2}%</td><td data-v=\"{bytes}\">{bytes_text}</td>\
+                 <td data-v=\"{self_pct}\"><div class=\"bar\" style=\"width:{bar:.1}%\"></div></td></tr>",
+                rank = ranks[id].unwrap_or(usize::MAX),
+                rank_text = ranks[id].map_or_else(|| "-".to_string(), |rank| format!("#{}", rank)),
+                name = name(id),
+                calls = anchor.calls,
+                bytes = anchor.bytes,
+                bytes_text = if anchor.bytes == 0 { String::new() } else { crate::format_bytes(anchor.bytes as u64) },
+                bar = self_pct.clamp(0.0, 100.0),
+                prec = self.precision,
+            );
+            let mut callers = Vec::new();
+            let mut callees = Vec::new();
+            for (&(parent, child), edge) in &self.edges {
+                let line = |other: usize| {
+                    format!("<li>{} &times;{} ({:.prec$}ms)</li>", name(other), edge.calls, ms(edge.elapsed_inclusive), prec = self.precision)
+                };
+                if child == id && parent != id {
+                    callers.push(line(parent));
+                }
+                if parent == id && child != id {
+                    callees.push(line(child));
+                }
+            }
+            let none = || "<li>none</li>".to_string();
+            let _ = writeln!(
+                rows,
+                "<tr class=\"details\" hidden><td colspan=\"9\"><b>Called from</b><ul>{}</ul><b>Calls</b><ul>{}</ul></td></tr></tbody>",
+                if callers.is_empty() { none() } else { callers.concat() },
+                if callees.is_empty() { none() } else { callees.concat() },
+            );
+        }
+
+        let mut summary = String::new();
+        let _ = self.write_header(&mut summary, total_duration, freq, None);
+        let mut out = String::new();
+        let _ = write!(
+            out,
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>PProf Results</title>\n\
+             <style>{}</style>\n</head>\n<body>\n<pre>{}</pre>\n<table>\n<thead><tr>\
+             <th>Rank</th><th>Name</th><th>Calls</th><th>Total (ms)</th><th>Total %</th>\
+             <th>Self (ms)</th><th>Self %</th><th>Bytes</th><th>Self time</th></tr></thead>\n{}</table>\n\
+             <script>{}</script>\n</body>\n</html>\n",
+            HTML_STYLE,
+            escape_html(&summary),
+            rows,
+            HTML_SCRIPT,
+        );
+        out
+    }
+
+    /// Just for fun: renders the profile as a SuperCollider score, one note
+    /// per anchor, for when you want to hear where the time went. Each note
+    /// lasts the anchor's total time in seconds and is pitched between 220Hz
+    /// and 880Hz by the anchor's share of the self time, so hot code sounds
+    /// high. Paste it into the SuperCollider IDE and evaluate it.
+    #[cfg(feature = "supercollider")]
+    pub fn export_supercollider_score(&mut self) -> String {
+        let anchors: Vec<AnchorResult> = self.results().anchors.into_iter().filter(|a| a.calls != 0).collect();
+        let total_self_ms: f64 = anchors.iter().map(|a| a.exclusive_ms).sum();
+        let mut names = Vec::new();
+        let mut durations = Vec::new();
+        let mut frequencies = Vec::new();
+        for anchor in &anchors {
+            let share = if total_self_ms > 0.0 { anchor.exclusive_ms / total_self_ms } else { 0.0 };
+            names.push(anchor.name.replace('\n', " "));
+            durations.push(format!("{:.4}", anchor.inclusive_ms / 1000.0));
+            frequencies.push(format!("{:.1}", 220.0 + share * 660.0));
+        }
+
+        let mut out = String::new();
+        let _ = writeln!(out, "// pprof score: {}", names.join(", "));
+        let _ = writeln!(out, "(");
+        let _ = writeln!(out, "s.waitForBoot {{");
+        let _ = writeln!(out, "    Pbind(");
+        let _ = writeln!(out, "        \\dur, Pseq([{}]),", durations.join(", "));
+        let _ = writeln!(out, "        \\freq, Pseq([{}])", frequencies.join(", "));
+        let _ = writeln!(out, "    ).play;");
+        let _ = writeln!(out, "}}");
+        let _ = writeln!(out, ")");
+        out
+    }
+
+    /// Returns the statistics as `PPROF_<ANCHOR>_<METRIC>` variables, e.g.
+    /// `PPROF_PARSE_JSON_SELF_MS`, for passing to CI steps. Anchor names are
+    /// uppercased with every non-alphanumeric character replaced by `_`.
+    pub fn report_as_env_vars(&mut self) -> HashMap<String, String> {
+        let mut vars = HashMap::new();
+        for anchor in &self.results().anchors {
+            let name: String = anchor
+                .name
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+                .collect();
+            vars.insert(format!("PPROF_{}_CALLS", name), anchor.calls.to_string());
+            vars.insert(format!("PPROF_{}_BYTES", name), anchor.bytes.to_string());
+            vars.insert(format!("PPROF_{}_SELF_MS", name), format!("{:.prec$}", anchor.exclusive_ms, prec = self.precision));
+            vars.insert(format!("PPROF_{}_TOTAL_MS", name), format!("{:.prec$}", anchor.inclusive_ms, prec = self.precision));
+        }
+        vars
+    }
+
+    /// Returns every statistic as a flat map with keys like
+    /// `pprof.<anchor>.self_ms`, `pprof.<anchor>.total_ms` and
+    /// `pprof.<anchor>.calls`, plus `pprof.total_ms`, for feeding any
+    /// monitoring backend.
+    pub fn metrics(&mut self) -> HashMap<String, f64> {
+        let mut metrics = HashMap::new();
+        for anchor in &self.results().anchors {
+            metrics.insert(format!("pprof.{}.self_ms", anchor.name), anchor.exclusive_ms);
+            metrics.insert(format!("pprof.{}.total_ms", anchor.name), anchor.inclusive_ms);
+            metrics.insert(format!("pprof.{}.calls", anchor.name), anchor.calls as f64);
+        }
+        metrics.insert("pprof.total_ms".to_string(), self.elapsed().as_secs_f64() * 1000.0);
+        metrics
+    }
+
+    /// Writes the profile in pprof's own binary format: the `PPROF\0\0\0`
+    /// magic and a version byte, followed by one record per anchor made of a
+    /// `u16` name length, the name bytes, and `u64` inclusive nanoseconds,
+    /// exclusive nanoseconds, calls and bytes, then a `u16` comment length and
+    /// the comment bytes (empty when there is none). All integers are
+    /// little-endian. Version 1 files, which have no comments, still import.
+    pub fn export_binary(&self, path: &Path) -> io::Result<()> {
+        let mut out = Vec::new();
+        out.extend_from_slice(BINARY_MAGIC);
+        out.push(BINARY_VERSION);
+        for anchor in &self.results().anchors {
+            let name_len = u16::try_from(anchor.name.len()).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("anchor name too long: {}", anchor.name))
+            })?;
+            out.extend_from_slice(&name_len.to_le_bytes());
+            out.extend_from_slice(anchor.name.as_bytes());
+            out.extend_from_slice(&ms_to_ns(anchor.inclusive_ms).to_le_bytes());
+            out.extend_from_slice(&ms_to_ns(anchor.exclusive_ms).to_le_bytes());
+            out.extend_from_slice(&(anchor.calls as u64).to_le_bytes());
+            out.extend_from_slice(&(anchor.bytes as u64).to_le_bytes());
+            let comment = anchor.comment.as_deref().unwrap_or("");
+            let comment_len = u16::try_from(comment.len()).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("comment too long on anchor {}", anchor.name))
+            })?;
+            out.extend_from_slice(&comment_len.to_le_bytes());
+            out.extend_from_slice(comment.as_bytes());
+        }
+        File::create(path)?.write_all(&out)
+    }
+
+    /// Reads a profile written by [`Profiler::export_binary`].
+    pub fn import_binary(path: &Path) -> io::Result<ProfileResults> {
+        let mut data = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+
+        let mut reader = ByteReader { data: &data, pos: 0 };
+        if reader.take(BINARY_MAGIC.len())? != BINARY_MAGIC {
+            return Err(invalid_data("not a pprof binary profile"));
+        }
+        let version = reader.take(1)?[0];
+        if version == 0 || version > BINARY_VERSION {
+            return Err(invalid_data(&format!("unsupported binary profile version {}", version)));
+        }
+
+        let mut anchors = Vec::new();
+        while reader.pos < data.len() {
+            let name_len = u16::from_le_bytes(reader.array()?) as usize;
+            let name = String::from_utf8(reader.take(name_len)?.to_vec()).map_err(|_| ParseError::InvalidUtf8)?;
+            let inclusive_ns = u64::from_le_bytes(reader.array()?);
+            let exclusive_ns = u64::from_le_bytes(reader.array()?);
+            let calls = u64::from_le_bytes(reader.array()?);
+            let bytes = u64::from_le_bytes(reader.array()?);
+            // Version 1 files predate comments.
+            let comment = if version >= 2 {
+                let comment_len = u16::from_le_bytes(reader.array()?) as usize;
+                let comment = String::from_utf8(reader.take(comment_len)?.to_vec()).map_err(|_| ParseError::InvalidUtf8)?;
+                Some(comment).filter(|c| !c.is_empty())
+            } else {
+                None
+            };
+            anchors.push(AnchorResult {
+                name,
+                calls: calls as usize,
+                bytes: bytes as usize,
+                exclusive_ms: exclusive_ns as f64 / 1_000_000.0,
+                inclusive_ms: inclusive_ns as f64 / 1_000_000.0,
+                ticks_exclusive: exclusive_ns,
+                ticks_inclusive: inclusive_ns,
+                #[cfg(feature = "timeline")]
+                first_start_ns: 0,
+                #[cfg(feature = "timeline")]
+                last_end_ns: 0,
+                comment,
+                custom_metrics: BTreeMap::new(),
+            });
+        }
+        Ok(ProfileResults { anchors })
+    }
+
+    /// Packs the profile for telemetry payloads: a 12-byte header with a
+    /// `u32` anchor count and the clock's ticks per second as an `f64`, then
+    /// 26 + name length bytes per anchor: a `u16` name length, the name
+    /// bytes, `u64` exclusive and inclusive ticks, and `u32` calls and
+    /// kilobytes, all little-endian. Counts too large for a `u32` are capped,
+    /// names longer than 64 KiB are truncated.
+    pub fn export_compact_binary(&self) -> Vec<u8> {
+        let anchors = &self.anchors[1..];
+        let mut out = Vec::new();
+        out.extend_from_slice(&(anchors.len() as u32).to_le_bytes());
+        out.extend_from_slice(&self.freq().to_le_bytes());
+        for anchor in anchors {
+            let mut name_len = anchor.name.len().min(u16::MAX as usize);
+            while !anchor.name.is_char_boundary(name_len) {
+                name_len -= 1;
+            }
+            out.extend_from_slice(&(name_len as u16).to_le_bytes());
+            out.extend_from_slice(&anchor.name.as_bytes()[..name_len]);
+            out.extend_from_slice(&anchor.elapsed_exclusive.to_le_bytes());
+            out.extend_from_slice(&anchor.elapsed_inclusive.to_le_bytes());
+            out.extend_from_slice(&(anchor.calls.min(u32::MAX as usize) as u32).to_le_bytes());
+            out.extend_from_slice(&((anchor.bytes / 1024).min(u32::MAX as usize) as u32).to_le_bytes());
+        }
+        out
+    }
+
+    /// Reads data written by [`Profiler::export_compact_binary`]. Ticks are
+    /// converted to milliseconds with the frequency stored by the exporting
+    /// side, and kept as they are in the raw tick counts.
+    pub fn import_compact_binary(data: &[u8]) -> Result<ProfileResults, ParseError> {
+        let mut reader = ByteReader { data, pos: 0 };
+        let count = u32::from_le_bytes(reader.array()?) as usize;
+        let freq = f64::from_le_bytes(reader.array()?);
+        if !(freq.is_finite() && freq > 0.0) {
+            return Err(ParseError::InvalidFrequency);
+        }
+
+        // Every record takes at least 26 bytes, which bounds the allocation
+        // for corrupt counts.
+        let mut anchors = Vec::with_capacity(count.min(data.len() / 26));
+        for _ in 0..count {
+            let name_len = u16::from_le_bytes(reader.array()?) as usize;
+            let name = String::from_utf8(reader.take(name_len)?.to_vec()).map_err(|_| ParseError::InvalidUtf8)?;
+            let ticks_exclusive = u64::from_le_bytes(reader.array()?);
+            let ticks_inclusive = u64::from_le_bytes(reader.array()?);
+            let calls = u32::from_le_bytes(reader.array()?);
+            let kilobytes = u32::from_le_bytes(reader.array()?);
+            anchors.push(AnchorResult {
+                name,
+                calls: calls as usize,
+                bytes: kilobytes as usize * 1024,
+                exclusive_ms: ticks_exclusive as f64 / freq * 1000.0,
+                inclusive_ms: ticks_inclusive as f64 / freq * 1000.0,
+                ticks_exclusive,
+                ticks_inclusive,
+                #[cfg(feature = "timeline")]
+                first_start_ns: 0,
+                #[cfg(feature = "timeline")]
+                last_end_ns: 0,
+                comment: None,
+                custom_metrics: BTreeMap::new(),
+            });
+        }
+        if reader.pos != data.len() {
+            return Err(ParseError::TrailingData);
+        }
+        Ok(ProfileResults { anchors })
+    }
+
+    /// Reads a profile written by [`Profiler::write_csv`]. Raw tick counts
+    /// of the imported anchors are in nanoseconds.
+    pub fn import_csv(path: &Path) -> io::Result<ProfileResults> {
+        let mut text = String::new();
+        File::open(path)?.read_to_string(&mut text)?;
+
+        let mut records = parse_csv(&text)?.into_iter();
+        // Any columns after the fixed ones hold custom metrics.
+        let metric_keys = match records.next() {
+            Some((_, header)) if header.starts_with(&CSV_COLUMNS.map(String::from)) => header[CSV_COLUMNS.len()..].to_vec(),
+            _ => return Err(invalid_data("missing CSV header name,calls,bytes,self_ms,total_ms")),
+        };
+
+        let mut anchors = Vec::new();
+        for (line, mut record) in records {
+            let expected = CSV_COLUMNS.len() + metric_keys.len();
+            if record.len() != expected {
+                return Err(invalid_data(&format!("line {}: expected {} fields, found {}", line, expected, record.len())));
+            }
+            let metric_values = record.split_off(CSV_COLUMNS.len());
+            let [name, calls, bytes, self_ms, total_ms] = <[String; 5]>::try_from(record).unwrap();
+            let field = |value: &str, what: &str| {
+                invalid_data(&format!("line {}: invalid {} {:?}", line, what, value))
+            };
+            let calls = calls.parse().map_err(|_| field(&calls, "calls"))?;
+            let bytes = bytes.parse().map_err(|_| field(&bytes, "bytes"))?;
+            let exclusive_ms: f64 = self_ms.parse().map_err(|_| field(&self_ms, "self_ms"))?;
+            let inclusive_ms: f64 = total_ms.parse().map_err(|_| field(&total_ms, "total_ms"))?;
+            let mut custom_metrics = BTreeMap::new();
+            for (key, value) in metric_keys.iter().zip(&metric_values) {
+                // Anchors without this metric leave the field empty.
+                if !value.is_empty() {
+                    custom_metrics.insert(key.clone(), value.parse().map_err(|_| field(value, key))?);
+                }
+            }
+            anchors.push(AnchorResult {
+                name,
+                calls,
+                bytes,
+                exclusive_ms,
+                inclusive_ms,
+                ticks_exclusive: ms_to_ns(exclusive_ms),
+                ticks_inclusive: ms_to_ns(inclusive_ms),
+                #[cfg(feature = "timeline")]
+                first_start_ns: 0,
+                #[cfg(feature = "timeline")]
+                last_end_ns: 0,
+                comment: None,
+                custom_metrics,
+            });
+        }
+        Ok(ProfileResults { anchors })
+    }
+
+    /// Parses a profile written by [`Profiler::write_json`]. Fields it
+    /// doesn't know, e.g. ones added by hand, are ignored. Raw tick counts of
+    /// the imported anchors are in nanoseconds.
+    pub fn import_json(json: &str) -> Result<ProfileResults, ParseError> {
+        let mut parser = JsonParser { text: json, pos: 0 };
+        let root = parser.value()?;
+        parser.skip_whitespace();
+        if parser.pos != json.len() {
+            return Err(ParseError::TrailingData);
+        }
+
+        let invalid = |pos: usize| json_error(json, pos);
+        let (root_pos, JsonValue::Object(root)) = root else {
+            return Err(invalid(root.0));
+        };
+        let (anchors_pos, anchors) = json_field(&root, "anchors").ok_or_else(|| invalid(root_pos))?;
+        let JsonValue::Array(anchors) = anchors else {
+            return Err(invalid(*anchors_pos));
+        };
+
+        let mut results = Vec::with_capacity(anchors.len());
+        for (pos, anchor) in anchors {
+            let JsonValue::Object(fields) = anchor else {
+                return Err(invalid(*pos));
+            };
+            let number = |key: &str| match json_field(fields, key) {
+                Some((_, JsonValue::Number(n))) => Ok(*n),
+                Some((pos, _)) => Err(invalid(*pos)),
+                None => Err(invalid(*pos)),
+            };
+            let name = match json_field(fields, "name") {
+                Some((_, JsonValue::String(name))) => name.clone(),
+                Some((pos, _)) => return Err(invalid(*pos)),
+                None => return Err(invalid(*pos)),
+            };
+            let exclusive_ms = number("self_ms")?;
+            let inclusive_ms = number("total_ms")?;
+            let mut custom_metrics = BTreeMap::new();
+            match json_field(fields, "custom_metrics") {
+                Some((_, JsonValue::Object(metrics))) => {
+                    for (key, (pos, value)) in metrics {
+                        let JsonValue::Number(value) = value else {
+                            return Err(invalid(*pos));
+                        };
+                        custom_metrics.insert(key.clone(), *value);
+                    }
+                }
+                Some((pos, _)) => return Err(invalid(*pos)),
+                None => {}
+            }
+            results.push(AnchorResult {
+                name,
+                calls: number("calls")? as usize,
+                bytes: number("bytes")? as usize,
+                exclusive_ms,
+                inclusive_ms,
+                ticks_exclusive: ms_to_ns(exclusive_ms),
+                ticks_inclusive: ms_to_ns(inclusive_ms),
+                #[cfg(feature = "timeline")]
+                first_start_ns: 0,
+                #[cfg(feature = "timeline")]
+                last_end_ns: 0,
+                comment: None,
+                custom_metrics,
+            });
+        }
+        Ok(ProfileResults { anchors: results })
+    }
+
+    /// Writes the profile in the given format. New formats are added by
+    /// extending [`OutputFormat`] and dispatching to their writer here.
+    pub fn export_to_writer_with_format<W: Write>(&mut self, w: W, format: OutputFormat) -> io::Result<()> {
+        match format {
+            OutputFormat::Text => self.write_text(w),
+            OutputFormat::Json => self.write_json(w),
+            OutputFormat::Csv => self.write_csv(w),
+            OutputFormat::FoldedStacks => self.write_folded_stacks(w),
+            OutputFormat::ChromeTrace => self.write_chrome_trace(w),
+        }
+    }
+
+    /// Writes the same report as [`Profiler::print`].
+    pub fn write_text<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let mut report = String::new();
+        let _ = self.write_report(&mut report);
+        w.write_all(report.as_bytes())
+    }
+
+    /// Writes `{"total_ms": ..., "anchors": [{"name", "calls", "bytes",
+    /// "self_ms", "total_ms"}, ...]}`.
+    pub fn write_json<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let total_ms = self.elapsed().as_secs_f64() * 1000.0;
+        let mut out = String::new();
+        let _ = write!(out, "{{\"total_ms\":{},\"anchors\":[", total_ms);
+        for (i, anchor) in self.results().anchors.iter().enumerate() {
+            if i != 0 {
+                out.push(',');
+            }
+            let _ = write!(
+                out,
+                "{{\"name\":\"{}\",\"calls\":{},\"bytes\":{},\"self_ms\":{},\"total_ms\":{}",
+                escape_json(&anchor.name),
+                anchor.calls,
+                anchor.bytes,
+                anchor.exclusive_ms,
+                anchor.inclusive_ms,
+            );
+            if !anchor.custom_metrics.is_empty() {
+                let metrics: Vec<String> = anchor
+                    .custom_metrics
+                    .iter()
+                    .map(|(key, value)| format!("\"{}\":{}", escape_json(key), value))
+                    .collect();
+                let _ = write!(out, ",\"custom_metrics\":{{{}}}", metrics.join(","));
+            }
+            out.push('}');
+        }
+        out.push_str("]}\n");
+        w.write_all(out.as_bytes())
+    }
+
+    /// Writes the output of [`Profiler::write_json`] gzip-compressed.
+    #[cfg(feature = "gzip")]
+    pub fn export_gzip_json<W: Write>(&mut self, w: W) -> io::Result<()> {
+        let mut encoder = flate2::write::GzEncoder::new(w, flate2::Compression::default());
+        self.write_json(&mut encoder)?;
+        encoder.finish()?.flush()
+    }
+
+    /// Writes the output of [`Profiler::export_compact_binary`]
+    /// zstd-compressed, which shrinks profiles with many similar anchor names
+    /// a lot.
+    #[cfg(feature = "zstd")]
+    pub fn report_zstd_binary<W: Write>(&mut self, w: W) -> io::Result<()> {
+        zstd::stream::copy_encode(self.export_compact_binary().as_slice(), w, 0)
+    }
+
+    /// Reads a profile written by [`Profiler::report_zstd_binary`].
+    #[cfg(feature = "zstd")]
+    pub fn import_zstd_binary<R: Read>(r: R) -> io::Result<ProfileResults> {
+        let data = zstd::stream::decode_all(r)?;
+        Ok(Self::import_compact_binary(&data)?)
+    }
+
+    /// Writes the profile as MessagePack: a map with `total_ns` and an
+    /// `anchors` array of maps with `name`, `calls`, `bytes`, `self_ns` and
+    /// `total_ns`, plus `comment` and `custom_metrics` (a map of floats)
+    /// when the anchor has them. Smaller than [`Profiler::write_json`] and
+    /// readable from most languages.
+    #[cfg(feature = "msgpack")]
+    pub fn export_msgpack<W: Write>(&mut self, mut w: W) -> io::Result<()> {
+        let results = self.results();
+        let mut out = Vec::new();
+        msgpack_map_len(&mut out, 2);
+        msgpack_str(&mut out, "total_ns");
+        msgpack_uint(&mut out, self.elapsed().as_nanos() as u64);
+        msgpack_str(&mut out, "anchors");
+        msgpack_array_len(&mut out, results.anchors.len());
+        for anchor in &results.anchors {
+            let fields = 5 + anchor.comment.is_some() as usize + !anchor.custom_metrics.is_empty() as usize;
+            msgpack_map_len(&mut out, fields);
+            msgpack_str(&mut out, "name");
+            msgpack_str(&mut out, &anchor.name);
+            msgpack_str(&mut out, "calls");
+            msgpack_uint(&mut out, anchor.calls as u64);
+            msgpack_str(&mut out, "bytes");
+            msgpack_uint(&mut out, anchor.bytes as u64);
+            msgpack_str(&mut out, "self_ns");
+            msgpack_uint(&mut out, ms_to_ns(anchor.exclusive_ms));
+            msgpack_str(&mut out, "total_ns");
+            msgpack_uint(&mut out, ms_to_ns(anchor.inclusive_ms));
+            if let Some(comment) = &anchor.comment {
+                msgpack_str(&mut out, "comment");
+                msgpack_str(&mut out, comment);
+            }
+            if !anchor.custom_metrics.is_empty() {
+                msgpack_str(&mut out, "custom_metrics");
+                msgpack_map_len(&mut out, anchor.custom_metrics.len());
+                for (key, &value) in &anchor.custom_metrics {
+                    msgpack_str(&mut out, key);
+                    out.push(0xcb);
+                    out.extend_from_slice(&value.to_be_bytes());
+                }
+            }
+        }
+        w.write_all(&out)
+    }
+
+    /// Reads a profile written by [`Profiler::export_msgpack`]. Unknown map
+    /// keys are ignored. Raw tick counts of the imported anchors are in
+    /// nanoseconds.
+    #[cfg(feature = "msgpack")]
+    pub fn import_msgpack<R: Read>(mut r: R) -> io::Result<ProfileResults> {
+        let mut data = Vec::new();
+        r.read_to_end(&mut data)?;
+        let mut reader = ByteReader { data: &data, pos: 0 };
+        let root = msgpack_value(&mut reader, 0)?;
+        if reader.pos != data.len() {
+            return Err(ParseError::TrailingData.into());
+        }
+
+        let MsgpackValue::Map(root) = root else {
+            return Err(invalid_data("MessagePack profile is not a map"));
+        };
+        let Some(MsgpackValue::Array(anchors)) = msgpack_field(&root, "anchors") else {
+            return Err(invalid_data("MessagePack profile has no anchors array"));
+        };
+        let mut results = Vec::with_capacity(anchors.len());
+        for anchor in anchors {
+            let MsgpackValue::Map(fields) = anchor else {
+                return Err(invalid_data("MessagePack anchor is not a map"));
+            };
+            let uint = |key: &str| match msgpack_field(fields, key) {
+                Some(&MsgpackValue::Uint(n)) => Ok(n),
+                _ => Err(invalid_data(&format!("MessagePack anchor has no integer {}", key))),
+            };
+            let Some(MsgpackValue::Str(name)) = msgpack_field(fields, "name") else {
+                return Err(invalid_data("MessagePack anchor has no name"));
+            };
+            let comment = match msgpack_field(fields, "comment") {
+                Some(MsgpackValue::Str(comment)) => Some(comment.clone()),
+                None => None,
+                Some(_) => return Err(invalid_data(&format!("invalid comment on anchor {}", name))),
+            };
+            let mut custom_metrics = BTreeMap::new();
+            match msgpack_field(fields, "custom_metrics") {
+                Some(MsgpackValue::Map(metrics)) => {
+                    for (key, value) in metrics {
+                        let &MsgpackValue::Float(value) = value else {
+                            return Err(invalid_data(&format!("invalid custom metric {} on anchor {}", key, name)));
+                        };
+                        custom_metrics.insert(key.clone(), value);
+                    }
+                }
+                None => {}
+                Some(_) => return Err(invalid_data(&format!("invalid custom metrics on anchor {}", name))),
+            }
+            let (exclusive_ns, inclusive_ns) = (uint("self_ns")?, uint("total_ns")?);
+            results.push(AnchorResult {
+                name: name.clone(),
+                calls: uint("calls")? as usize,
+                bytes: uint("bytes")? as usize,
+                exclusive_ms: exclusive_ns as f64 / 1_000_000.0,
+                inclusive_ms: inclusive_ns as f64 / 1_000_000.0,
+                ticks_exclusive: exclusive_ns,
+                ticks_inclusive: inclusive_ns,
+                #[cfg(feature = "timeline")]
+                first_start_ns: 0,
+                #[cfg(feature = "timeline")]
+                last_end_ns: 0,
+                comment,
+                custom_metrics,
+            });
+        }
+        Ok(ProfileResults { anchors: results })
+    }
+
+    /// Emits one `pprof_anchor` span at `TRACE` level per anchor to the
+    /// current `tracing` subscriber, with the anchor name and its statistics
+    /// as fields. Span names have to be static, hence the shared name.
+    #[cfg(feature = "tracing-compat")]
+    pub fn report_as_tracing_spans(&mut self) {
+        for anchor in &self.results().anchors {
+            let span = tracing::trace_span!(
+                "pprof_anchor",
+                anchor = %anchor.name,
+                calls = anchor.calls as u64,
+                bytes = anchor.bytes as u64,
+                self_ms = anchor.exclusive_ms,
+                total_ms = anchor.inclusive_ms,
+            );
+            span.in_scope(|| {});
+        }
+    }
+
+    /// Sets one `metrics` gauge per entry of [`Profiler::metrics`] on the
+    /// installed recorder.
+    #[cfg(feature = "metrics-compat")]
+    pub fn report_as_metrics_gauges(&mut self) {
+        for (key, value) in self.metrics() {
+            metrics::gauge!(key).set(value);
+        }
+    }
+
+    /// Writes one `name,calls,bytes,self_ms,total_ms` row per anchor after a
+    /// header row, plus a column per custom metric key that is left empty for
+    /// anchors without it. Names are quoted when they contain separators or
+    /// quotes.
+    pub fn write_csv<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let anchors = self.results().anchors;
+        let mut metric_keys: Vec<&str> = anchors.iter().flat_map(|a| a.custom_metrics.keys()).map(|k| k.as_str()).collect();
+        metric_keys.sort_unstable();
+        metric_keys.dedup();
+
+        let mut out = CSV_COLUMNS.join(",");
+        for key in &metric_keys {
+            let _ = write!(out, ",{}", escape_csv(key));
+        }
+        out.push('\n');
+        for anchor in &anchors {
+            let _ = write!(
+                out,
+                "{},{},{},{},{}",
+                escape_csv(&anchor.name),
+                anchor.calls,
+                anchor.bytes,
+                anchor.exclusive_ms,
+                anchor.inclusive_ms,
+            );
+            for key in &metric_keys {
+                out.push(',');
+                if let Some(value) = anchor.custom_metrics.get(*key) {
+                    let _ = write!(out, "{}", value);
+                }
+            }
+            out.push('\n');
+        }
+        w.write_all(out.as_bytes())
+    }
+
+    /// Writes one `outer;inner;leaf <self ns>` line per call path, as read by
+    /// `flamegraph.pl` and most other flame graph tools. An anchor reached
+    /// through several parents has its self time split between the paths in
+    /// proportion to the time each parent spent calling it.
+    pub fn write_folded_stacks<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let freq = self.freq();
+        let mut out = String::new();
+        self.visit_call_paths(|path, fraction, _| {
+            let leaf = &self.anchors[*path.last().unwrap()];
+            let self_ns = (leaf.elapsed_exclusive as f64 / freq * 1e9 * fraction).round();
+            if self_ns < 1.0 {
+                return;
+            }
+            let frames: Vec<String> = path.iter().map(|&id| self.anchors[id].name.replace(';', ":")).collect();
+            let _ = writeln!(out, "{} {}", frames.join(";"), self_ns);
+        });
+        w.write_all(out.as_bytes())
+    }
+
+    /// Writes a Chrome trace (`chrome://tracing`, Perfetto) with one complete
+    /// event per call path. Only aggregate times are recorded, so children
+    /// are laid out back to back from the start of their parent.
+    pub fn write_chrome_trace<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let freq = self.freq();
+        let pid = std::process::id();
+        let mut out = String::from("{\"traceEvents\":[");
+        // Next free timestamp (in µs) per depth within the current parent.
+        let mut cursor = vec![0.0];
+        let mut first = true;
+        self.visit_call_paths(|path, _, inclusive| {
+            let depth = path.len();
+            let dur = inclusive / freq * 1e6;
+            cursor.truncate(depth);
+            let ts = cursor[depth - 1];
+            cursor[depth - 1] += dur;
+            cursor.push(ts);
+
+            if !first {
+                out.push(',');
+            }
+            first = false;
+            let _ = write!(
+                out,
+                "{{\"name\":\"{}\",\"ph\":\"X\",\"ts\":{},\"dur\":{},\"pid\":{},\"tid\":1}}",
+                escape_json(&self.anchors[path[depth - 1]].name),
+                ts,
+                dur,
+                pid,
+            );
+        });
+        out.push_str("],\"displayTimeUnit\":\"ms\"}\n");
+        w.write_all(out.as_bytes())
+    }
+
+    /// Renders the profile as a DHAT (Valgrind) JSON file in ad-hoc mode, with
+    /// one program point per call path, so it can be explored in the DHAT
+    /// viewer (`dh_view.html`). The viewer's "units" are microseconds of self
+    /// time and its "events" are calls.
+    pub fn report_as_dhat_json(&mut self) -> String {
+        let freq = self.freq();
+        let mut pps = Vec::new();
+        self.visit_call_paths(|path, fraction, _| {
+            let leaf = &self.anchors[*path.last().unwrap()];
+            let self_us = (leaf.elapsed_exclusive as f64 / freq * 1e6 * fraction).round() as u64;
+            let calls = (leaf.calls as f64 * fraction).round() as u64;
+            // DHAT stacks list the innermost frame first. Frame indices are
+            // anchor IDs, with the root sentinel as DHAT's `[root]`.
+            let frames: Vec<String> = path.iter().rev().map(|id| id.to_string()).collect();
+            pps.push(format!("{{\"tb\":{},\"tbk\":{},\"fs\":[{}]}}", self_us, calls, frames.join(",")));
+        });
+        let mut frames = vec!["\"[root]\"".to_string()];
+        frames.extend(self.anchors[1..].iter().map(|a| format!("\"{}\"", escape_json(&a.name))));
+        let cmd: Vec<String> = std::env::args().collect();
+
+        let mut out = String::new();
+        let _ = write!(
+            out,
+            "{{\"dhatFileVersion\":2,\"mode\":\"ad-hoc\",\"verb\":\"Occurred\",\"bklt\":false,\"bkacc\":false,\
+             \"bu\":\"µs\",\"bsu\":\"µs\",\"bksu\":\"calls\",\"tu\":\"µs\",\"Mtu\":\"s\",\"tuth\":10,\
+             \"cmd\":\"{}\",\"pid\":{},\"te\":{},\"pps\":[{}],\"ftbl\":[{}]}}",
+            escape_json(&cmd.join(" ")),
+            std::process::id(),
+            self.elapsed().as_micros(),
+            pps.join(","),
+            frames.join(","),
+        );
+        out
+    }
+
+    // Walks every acyclic call path from the root in depth-first order. The
+    // visitor gets the path (anchor ids, root excluded), the fraction of the
+    // leaf's time attributed to this path, and the leaf's inclusive ticks
+    // along it.
+    pub(crate) fn visit_call_paths<F: FnMut(&[usize], f64, f64)>(&self, mut visit: F) {
+        let mut incoming = vec![0u64; self.anchors.len()];
+        for (&(parent, child), edge) in &self.edges {
+            if parent != child {
+                incoming[child] += edge.elapsed_inclusive;
+            }
+        }
+        let mut path = Vec::new();
+        self.visit_call_paths_from(0, 1.0, &incoming, &mut path, &mut visit);
+    }
+
+    fn visit_call_paths_from<F: FnMut(&[usize], f64, f64)>(
+        &self,
+        node: usize,
+        fraction: f64,
+        incoming: &[u64],
+        path: &mut Vec<usize>,
+        visit: &mut F,
+    ) {
+        for (&(_, child), edge) in self.edges.range((node, 0)..(node + 1, 0)) {
+            if child == 0 || path.contains(&child) || incoming[child] == 0 {
+                continue;
+            }
+            let share = edge.elapsed_inclusive as f64 / incoming[child] as f64;
+            path.push(child);
+            visit(path, fraction * share, edge.elapsed_inclusive as f64 * fraction);
+            self.visit_call_paths_from(child, fraction * share, incoming, path, visit);
+            path.pop();
+        }
+    }
+}
+
+impl FrozenProfiler {
+    /// Renders the snapshot in the Prometheus text exposition format, with
+    /// one series per anchor labelled `anchor="<name>"`.
+    pub fn report_prometheus(&self) -> String {
+        let mut out = String::new();
+        for &(metric, kind, help, value) in PROMETHEUS_METRICS {
+            let _ = writeln!(out, "# HELP {} {}", metric, help);
+            let _ = writeln!(out, "# TYPE {} {}", metric, kind);
+            for anchor in self.anchors_iter() {
+                let _ = writeln!(
+                    out,
+                    "{}{{anchor=\"{}\"}} {}",
+                    metric,
+                    escape_prometheus_label(&anchor.name),
+                    value(anchor),
+                );
+            }
+        }
+        let _ = writeln!(out, "# HELP pprof_elapsed_seconds Time since the profiler was reset.");
+        let _ = writeln!(out, "# TYPE pprof_elapsed_seconds gauge");
+        let _ = writeln!(out, "pprof_elapsed_seconds {}", self.total_ms() / 1000.0);
+        out
+    }
+}
+
+/// The formats supported by [`Profiler::export_to_writer_with_format`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+    Csv,
+    FoldedStacks,
+    ChromeTrace,
+}
+
+// Splits CSV text into records, each tagged with the line it starts on.
+// Quoted fields may contain separators, newlines and doubled quotes.
+fn parse_csv(text: &str) -> io::Result<Vec<(usize, Vec<String>)>> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut line = 1;
+    let mut start_line = 1;
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted => {
+                if chars.peek() == Some(&'"') {
+                    chars.next();
+                    field.push('"');
+                } else {
+                    quoted = false;
+                }
+            }
+            '"' if field.is_empty() => quoted = true,
+            '\n' if quoted => {
+                line += 1;
+                field.push(c);
+            }
+            ',' if !quoted => record.push(std::mem::take(&mut field)),
+            '\r' if !quoted && chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                record.push(std::mem::take(&mut field));
+                records.push((start_line, std::mem::take(&mut record)));
+                line += 1;
+                start_line = line;
+            }
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(invalid_data(&format!("line {}: unterminated quoted field", start_line)));
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push((start_line, record));
+    }
+    Ok(records)
+}
+
+struct ByteReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], ParseError> {
+        let bytes = self.data.get(self.pos..self.pos + n).ok_or(ParseError::UnexpectedEof)?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], ParseError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+}
+
+// A parsed JSON value. Object members and array elements keep the byte
+// offset they start at, for error positions.
+enum JsonValue {
+    // `true`, `false` or `null`, which no field of the format uses.
+    Literal,
+    Number(f64),
+    String(String),
+    Array(Vec<(usize, JsonValue)>),
+    Object(Vec<(String, (usize, JsonValue))>),
+}
+
+fn json_field<'a>(fields: &'a [(String, (usize, JsonValue))], key: &str) -> Option<&'a (usize, JsonValue)> {
+    fields.iter().find(|(k, _)| k == key).map(|(_, value)| value)
+}
+
+// The 1-based line and column of byte offset `pos`.
+fn json_error(text: &str, pos: usize) -> ParseError {
+    let before = &text[..pos.min(text.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rsplit('\n').next().map_or(0, |l| l.chars().count()) + 1;
+    ParseError::InvalidJson { line, column }
+}
+
+// Just enough of a JSON parser for the files `write_json` produces.
+struct JsonParser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl JsonParser<'_> {
+    fn error(&self) -> ParseError {
+        if self.pos >= self.text.len() {
+            ParseError::UnexpectedEof
+        } else {
+            json_error(self.text, self.pos)
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.as_bytes().get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), ParseError> {
+        self.skip_whitespace();
+        if self.peek() != Some(c) {
+            return Err(self.error());
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn literal(&mut self, word: &str) -> Result<JsonValue, ParseError> {
+        if !self.text[self.pos..].starts_with(word) {
+            return Err(self.error());
+        }
+        self.pos += word.len();
+        Ok(JsonValue::Literal)
+    }
+
+    fn value(&mut self) -> Result<(usize, JsonValue), ParseError> {
+        self.skip_whitespace();
+        let start = self.pos;
+        let value = match self.peek() {
+            Some(b'{') => self.object()?,
+            Some(b'[') => self.array()?,
+            Some(b'"') => JsonValue::String(self.string()?),
+            Some(b't') => self.literal("true")?,
+            Some(b'f') => self.literal("false")?,
+            Some(b'n') => self.literal("null")?,
+            Some(b'-' | b'0'..=b'9') => self.number()?,
+            _ => return Err(self.error()),
+        };
+        Ok((start, value))
+    }
+
+    fn object(&mut self) -> Result<JsonValue, ParseError> {
+        self.expect(b'{')?;
+        let mut fields = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(JsonValue::Object(fields));
+        }
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some(b'"') {
+                return Err(self.error());
+            }
+            let key = self.string()?;
+            self.expect(b':')?;
+            fields.push((key, self.value()?));
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Ok(JsonValue::Object(fields));
+                }
+                _ => return Err(self.error()),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<JsonValue, ParseError> {
+        self.expect(b'[')?;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(JsonValue::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {
+                    self.pos += 1;
+                    return Ok(JsonValue::Array(items));
+                }
+                _ => return Err(self.error()),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<JsonValue, ParseError> {
+        let start = self.pos;
+        while matches!(self.peek(), Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9')) {
+            self.pos += 1;
+        }
+        match self.text[start..self.pos].parse() {
+            Ok(n) => Ok(JsonValue::Number(n)),
+            Err(_) => Err(json_error(self.text, start)),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, ParseError> {
+        self.expect(b'"')?;
+        let mut s = String::new();
+        let mut chars = self.text[self.pos..].char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.pos += i + 1;
+                    return Ok(s);
+                }
+                '\\' => {
+                    let escape_pos = self.pos + i;
+                    let escaped = match chars.next() {
+                        Some((_, '"')) => '"',
+                        Some((_, '\\')) => '\\',
+                        Some((_, '/')) => '/',
+                        Some((_, 'b')) => '\u{8}',
+                        Some((_, 'f')) => '\u{c}',
+                        Some((_, 'n')) => '\n',
+                        Some((_, 'r')) => '\r',
+                        Some((_, 't')) => '\t',
+                        Some((_, 'u')) => {
+                            let hex: String = chars.by_ref().take(4).map(|(_, c)| c).collect();
+                            u32::from_str_radix(&hex, 16)
+                                .ok()
+                                .and_then(char::from_u32)
+                                .ok_or_else(|| json_error(self.text, escape_pos))?
+                        }
+                        _ => return Err(json_error(self.text, escape_pos)),
+                    };
+                    s.push(escaped);
+                }
+                c => s.push(c),
+            }
+        }
+        self.pos = self.text.len();
+        Err(ParseError::UnexpectedEof)
+    }
+}
+
+// The MessagePack encoding, as far as `export_msgpack` needs it: unsigned
+// integers, strings, float64, arrays and maps, each in its smallest form.
+#[cfg(feature = "msgpack")]
+fn msgpack_uint(out: &mut Vec<u8>, n: u64) {
+    if n < 0x80 {
+        out.push(n as u8);
+    } else if n <= u8::MAX as u64 {
+        out.extend_from_slice(&[0xcc, n as u8]);
+    } else if n <= u16::MAX as u64 {
+        out.push(0xcd);
+        out.extend_from_slice(&(n as u16).to_be_bytes());
+    } else if n <= u32::MAX as u64 {
+        out.push(0xce);
+        out.extend_from_slice(&(n as u32).to_be_bytes());
+    } else {
+        out.push(0xcf);
+        out.extend_from_slice(&n.to_be_bytes());
+    }
+}
+
+// Writes the header of a string, array or map of `len` items: the fix form
+// if there is one, else the 8 (strings only), 16 or 32-bit one.
+#[cfg(feature = "msgpack")]
+fn msgpack_header(out: &mut Vec<u8>, len: usize, fix: (u8, usize), str8: bool, wide: [u8; 2]) {
+    if len < fix.1 {
+        out.push(fix.0 | len as u8);
+    } else if str8 && len <= u8::MAX as usize {
+        out.extend_from_slice(&[0xd9, len as u8]);
+    } else if len <= u16::MAX as usize {
+        out.push(wide[0]);
+        out.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        out.push(wide[1]);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+}
+
+#[cfg(feature = "msgpack")]
+fn msgpack_str(out: &mut Vec<u8>, s: &str) {
+    msgpack_header(out, s.len(), (0xa0, 32), true, [0xda, 0xdb]);
+    out.extend_from_slice(s.as_bytes());
+}
+
+#[cfg(feature = "msgpack")]
+fn msgpack_array_len(out: &mut Vec<u8>, len: usize) {
+    msgpack_header(out, len, (0x90, 16), false, [0xdc, 0xdd]);
+}
+
+#[cfg(feature = "msgpack")]
+fn msgpack_map_len(out: &mut Vec<u8>, len: usize) {
+    msgpack_header(out, len, (0x80, 16), false, [0xde, 0xdf]);
+}
+
+// A decoded MessagePack value of one of the types `export_msgpack` writes.
+#[cfg(feature = "msgpack")]
+enum MsgpackValue {
+    Uint(u64),
+    Float(f64),
+    Str(String),
+    Array(Vec<MsgpackValue>),
+    Map(Vec<(String, MsgpackValue)>),
+}
+
+#[cfg(feature = "msgpack")]
+fn msgpack_field<'a>(fields: &'a [(String, MsgpackValue)], key: &str) -> Option<&'a MsgpackValue> {
+    fields.iter().find(|(k, _)| k == key).map(|(_, value)| value)
+}
+
+// Deeper than any profile nests, so corrupt input can't exhaust the stack.
+#[cfg(feature = "msgpack")]
+const MSGPACK_MAX_DEPTH: usize = 8;
+
+#[cfg(feature = "msgpack")]
+fn msgpack_value(reader: &mut ByteReader, depth: usize) -> io::Result<MsgpackValue> {
+    if depth > MSGPACK_MAX_DEPTH {
+        return Err(invalid_data("MessagePack profile nests too deeply"));
+    }
+    let marker = reader.take(1)?[0];
+    let (kind, len) = match marker {
+        0x00..=0x7f => return Ok(MsgpackValue::Uint(marker as u64)),
+        0xcc => return Ok(MsgpackValue::Uint(reader.take(1)?[0] as u64)),
+        0xcd => return Ok(MsgpackValue::Uint(u16::from_be_bytes(reader.array()?) as u64)),
+        0xce => return Ok(MsgpackValue::Uint(u32::from_be_bytes(reader.array()?) as u64)),
+        0xcf => return Ok(MsgpackValue::Uint(u64::from_be_bytes(reader.array()?))),
+        0xcb => return Ok(MsgpackValue::Float(f64::from_be_bytes(reader.array()?))),
+        0xa0..=0xbf => (0xa0, (marker & 0x1f) as usize),
+        0x90..=0x9f => (0x90, (marker & 0x0f) as usize),
+        0x80..=0x8f => (0x80, (marker & 0x0f) as usize),
+        0xd9 => (0xa0, reader.take(1)?[0] as usize),
+        0xda => (0xa0, u16::from_be_bytes(reader.array()?) as usize),
+        0xdb => (0xa0, u32::from_be_bytes(reader.array()?) as usize),
+        0xdc => (0x90, u16::from_be_bytes(reader.array()?) as usize),
+        0xdd => (0x90, u32::from_be_bytes(reader.array()?) as usize),
+        0xde => (0x80, u16::from_be_bytes(reader.array()?) as usize),
+        0xdf => (0x80, u32::from_be_bytes(reader.array()?) as usize),
+        _ => return Err(invalid_data(&format!("unsupported MessagePack type 0x{:02x}", marker))),
+    };
+    // Every item takes at least one byte, which bounds the allocations
+    // for corrupt lengths.
+    let capacity = len.min(reader.data.len() - reader.pos);
+    Ok(match kind {
+        0xa0 => MsgpackValue::Str(String::from_utf8(reader.take(len)?.to_vec()).map_err(|_| ParseError::InvalidUtf8)?),
+        0x90 => {
+            let mut items = Vec::with_capacity(capacity);
+            for _ in 0..len {
+                items.push(msgpack_value(reader, depth + 1)?);
+            }
+            MsgpackValue::Array(items)
+        }
+        _ => {
+            let mut entries = Vec::with_capacity(capacity);
+            for _ in 0..len {
+                let MsgpackValue::Str(key) = msgpack_value(reader, depth + 1)? else {
+                    return Err(invalid_data("MessagePack map key is not a string"));
+                };
+                entries.push((key, msgpack_value(reader, depth + 1)?));
+            }
+            MsgpackValue::Map(entries)
+        }
+    })
+}
+
+/// Why a binary or JSON profile could not be read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    UnexpectedEof,
+    InvalidUtf8,
+    TrailingData,
+    /// A compact binary profile whose clock frequency isn't a positive number.
+    InvalidFrequency,
+    /// Malformed JSON, or JSON without the expected fields, at the given
+    /// 1-based position.
+    InvalidJson { line: usize, column: usize },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ParseError::UnexpectedEof => "unexpected end of profile",
+            ParseError::InvalidUtf8 => "anchor name is not valid UTF-8",
+            ParseError::TrailingData => "trailing data after the last anchor",
+            ParseError::InvalidFrequency => "invalid clock frequency in profile header",
+            ParseError::InvalidJson { line, column } => {
+                return write!(f, "invalid JSON profile at line {}, column {}", line, column);
+            }
+        })
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<ParseError> for io::Error {
+    fn from(e: ParseError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn ms_to_ns(ms: f64) -> u64 {
+    (ms * 1_000_000.0).round() as u64
+}
+
+#[cfg(feature = "html")]
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn escape_dot(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn escape_json(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn escape_csv(s: &str) -> Cow<'_, str> {
+    if s.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", s.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(s)
+    }
+}
+
+fn escape_prometheus_label(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::call_chain;
+    use crate::{OutputFormat, ParseError, ProfileResults, Profiler};
+    use std::fs::File;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    // A profiler with `n` top-level anchors, each with different times,
+    // calls and bytes.
+    fn sample_profiler(n: usize) -> Profiler {
+        let mut p = Profiler::new();
+        for i in 0..n {
+            let id = p.get_anchor_id(&format!("anchor{}", i));
+            for _ in 0..=i {
+                p.record_call(id, Duration::from_micros(100 * (i as u64 + 1)));
+            }
+            p.add_bytes(id, 1024 * i);
+        }
+        p
+    }
+
+    // Checks the fields every format keeps.
+    fn assert_same_anchors(imported: &ProfileResults, expected: &ProfileResults) {
+        assert_eq!(imported.anchors.len(), expected.anchors.len());
+        for (imported, expected) in imported.anchors.iter().zip(&expected.anchors) {
+            assert_eq!(imported.name, expected.name);
+            assert_eq!(imported.calls, expected.calls);
+            assert_eq!(imported.bytes, expected.bytes);
+            assert!((imported.inclusive_ms - expected.inclusive_ms).abs() < 1e-5, "{:?} {:?}", imported, expected);
+            assert!((imported.exclusive_ms - expected.exclusive_ms).abs() < 1e-5, "{:?} {:?}", imported, expected);
+        }
+    }
+
+    // A file in the temporary directory, unique to this test run.
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("pprof-test-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn compact_binary_round_trips() {
+        let mut p = Profiler::new();
+        let ids = [p.get_anchor_id("outer"), p.get_anchor_id("inner")];
+        call_chain(&mut p, &ids);
+        let imported = Profiler::import_compact_binary(&p.export_compact_binary()).unwrap();
+        let expected = p.results();
+        assert_eq!(imported.anchors.len(), expected.anchors.len());
+        for (imported, expected) in imported.anchors.iter().zip(&expected.anchors) {
+            assert_eq!(imported.name, expected.name);
+            assert_eq!(imported.calls, expected.calls);
+            assert!((imported.inclusive_ms - expected.inclusive_ms).abs() < 1e-6);
+            assert!((imported.exclusive_ms - expected.exclusive_ms).abs() < 1e-6);
+        }
+    }
+
+    #[cfg(feature = "rdtsc")]
+    #[test]
+    fn compact_binary_keeps_wall_clock_units() {
+        let mut p = Profiler::new();
+        p.set_wall_clock_mode(true);
+        let ids = [p.get_anchor_id("a")];
+        call_chain(&mut p, &ids);
+        let imported = Profiler::import_compact_binary(&p.export_compact_binary()).unwrap();
+        let expected = p.results();
+        assert!((imported.anchors[0].inclusive_ms - expected.anchors[0].inclusive_ms).abs() < 1e-6);
+    }
+
+    #[test]
+    fn compact_binary_converts_with_the_stored_frequency() {
+        let mut data = 1u32.to_le_bytes().to_vec();
+        data.extend_from_slice(&1000.0f64.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(b"a");
+        data.extend_from_slice(&2u64.to_le_bytes());
+        data.extend_from_slice(&5u64.to_le_bytes());
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        let imported = Profiler::import_compact_binary(&data).unwrap();
+        assert_eq!(imported.anchors[0].exclusive_ms, 2.0);
+        assert_eq!(imported.anchors[0].inclusive_ms, 5.0);
+    }
+
+    #[test]
+    fn compact_binary_rejects_a_zero_frequency() {
+        let mut data = 0u32.to_le_bytes().to_vec();
+        data.extend_from_slice(&0.0f64.to_le_bytes());
+        assert_eq!(Profiler::import_compact_binary(&data).unwrap_err(), crate::ParseError::InvalidFrequency);
+    }
+
+    #[test]
+    fn graphviz_draws_one_edge_per_caller_and_callee() {
+        let mut p = Profiler::new();
+        let ids = [p.get_anchor_id("a"), p.get_anchor_id("b"), p.get_anchor_id("c")];
+        call_chain(&mut p, &ids);
+        let dot = p.report_graphviz();
+        assert!(dot.starts_with("digraph"));
+        assert_eq!(dot.matches(" -> ").count(), 2);
+        assert!(dot.contains(&format!("n{} -> n{}", ids[0], ids[1])));
+        assert!(dot.contains(&format!("n{} -> n{}", ids[1], ids[2])));
+    }
+
+    #[test]
+    fn graphviz_uses_the_output_precision() {
+        let mut p = Profiler::new();
+        let ids = [p.get_anchor_id("a")];
+        call_chain(&mut p, &ids);
+        p.set_output_precision(1);
+        let dot = p.report_graphviz();
+        let self_ms = dot.split("self: ").nth(1).unwrap().split("ms").next().unwrap();
+        assert_eq!(self_ms.split('.').nth(1).map(str::len), Some(1), "{}", dot);
+    }
+
+    #[test]
+    fn graphviz_leaves_out_edges_into_the_overflow_bucket() {
+        let mut p = Profiler::new();
+        let a = p.get_anchor_id("a");
+        p.set_max_anchors(1);
+        let overflow = p.get_anchor_id("b");
+        assert_eq!(overflow, 0);
+        call_chain(&mut p, &[a, overflow]);
+        let dot = p.report_graphviz();
+        assert!(!dot.contains("n0"), "{}", dot);
+    }
+
+    #[test]
+    fn binary_round_trips() {
+        let mut p = sample_profiler(10);
+        p.set_anchor_comment("anchor3", "cached");
+        let path = temp_path("round-trip.bin");
+        p.export_binary(&path).unwrap();
+        let imported = Profiler::import_binary(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let expected = p.results();
+        assert_same_anchors(&imported, &expected);
+        let comments: Vec<_> = imported.anchors.iter().filter_map(|a| a.comment.as_deref()).collect();
+        assert_eq!(comments, ["cached"]);
+    }
+
+    #[test]
+    fn every_output_format_writes_its_own_syntax() {
+        let mut p = Profiler::new();
+        let ids = [p.get_anchor_id("outer"), p.get_anchor_id("inner")];
+        call_chain(&mut p, &ids);
+        let formats = [
+            (OutputFormat::Text, "--- PProf Results"),
+            (OutputFormat::Json, "{\"total_ms\":"),
+            (OutputFormat::Csv, "name,calls,bytes,self_ms,total_ms\n"),
+            (OutputFormat::FoldedStacks, "outer;inner "),
+            (OutputFormat::ChromeTrace, "{\"traceEvents\":["),
+        ];
+        for (format, sentinel) in formats {
+            let mut out = Vec::new();
+            p.export_to_writer_with_format(&mut out, format).unwrap();
+            let out = String::from_utf8(out).unwrap();
+            assert!(out.contains(sentinel), "{:?}", out);
+        }
+    }
+
+    #[test]
+    fn csv_round_trips() {
+        let p = sample_profiler(5);
+        let path = temp_path("round-trip.csv");
+        p.write_csv(File::create(&path).unwrap()).unwrap();
+        let imported = Profiler::import_csv(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_same_anchors(&imported, &p.results());
+    }
+
+    #[test]
+    fn env_vars_are_named_after_the_uppercased_anchor() {
+        let mut p = Profiler::new();
+        let parse = p.get_anchor_id("parse::json");
+        p.record_call(parse, Duration::from_millis(2));
+        p.add_bytes(parse, 100);
+        let io = p.get_anchor_id("io");
+        p.record_call(io, Duration::from_millis(1));
+        let vars = p.report_as_env_vars();
+        assert_eq!(vars.len(), 8);
+        assert_eq!(vars["PPROF_PARSE__JSON_CALLS"], "1");
+        assert_eq!(vars["PPROF_PARSE__JSON_BYTES"], "100");
+        assert_eq!(vars["PPROF_PARSE__JSON_SELF_MS"], "2.0000");
+        assert_eq!(vars["PPROF_PARSE__JSON_TOTAL_MS"], "2.0000");
+        assert_eq!(vars["PPROF_IO_CALLS"], "1");
+    }
+
+    #[test]
+    fn compact_binary_import_never_panics() {
+        let valid = sample_profiler(3).export_compact_binary();
+        // Every truncation and single-byte corruption of a valid profile,
+        // then random data.
+        for len in 0..valid.len() {
+            let _ = Profiler::import_compact_binary(&valid[..len]);
+        }
+        for i in 0..valid.len() {
+            for byte in [0x00, 0x7f, 0xff] {
+                let mut data = valid.clone();
+                data[i] = byte;
+                let _ = Profiler::import_compact_binary(&data);
+            }
+        }
+        let mut seed = 0x9e37_79b9_7f4a_7c15_u64;
+        let mut random = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+        for _ in 0..10_000 {
+            let len = random() % 64;
+            let data: Vec<u8> = (0..len).map(|_| random() as u8).collect();
+            let _ = Profiler::import_compact_binary(&data);
+        }
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn gzip_json_decompresses_to_the_json_profile() {
+        use std::io::Read;
+
+        let mut p = sample_profiler(4);
+        let mut compressed = Vec::new();
+        p.export_gzip_json(&mut compressed).unwrap();
+        let mut json = String::new();
+        flate2::read::GzDecoder::new(compressed.as_slice()).read_to_string(&mut json).unwrap();
+        assert_same_anchors(&Profiler::import_json(&json).unwrap(), &p.results());
+    }
+
+    #[test]
+    fn metrics_cover_every_anchor() {
+        let mut p = sample_profiler(3);
+        let metrics = p.metrics();
+        for i in 0..3 {
+            for stat in ["self_ms", "total_ms", "calls"] {
+                assert!(metrics[&format!("pprof.anchor{}.{}", i, stat)] >= 0.0);
+            }
+        }
+        assert!(metrics["pprof.total_ms"] >= 0.0);
+    }
+
+    #[test]
+    fn custom_metrics_survive_csv_and_json() {
+        let mut p = sample_profiler(3);
+        p.add_custom_metric("anchor1", "rows", 42.0);
+        p.add_custom_metric("anchor2", "hit_rate", 0.5);
+        let expected = p.results();
+        assert_eq!(expected.anchors[1].custom_metrics["rows"], 42.0);
+        let path = temp_path("metrics.csv");
+        p.write_csv(File::create(&path).unwrap()).unwrap();
+        let from_csv = Profiler::import_csv(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut json = Vec::new();
+        p.write_json(&mut json).unwrap();
+        let from_json = Profiler::import_json(std::str::from_utf8(&json).unwrap()).unwrap();
+        for imported in [from_csv, from_json] {
+            assert_same_anchors(&imported, &expected);
+            assert_eq!(imported.anchors[1].custom_metrics["rows"], 42.0);
+            assert_eq!(imported.anchors[2].custom_metrics["hit_rate"], 0.5);
+        }
+    }
+
+    #[test]
+    fn json_round_trips() {
+        let p = sample_profiler(10);
+        let mut json = Vec::new();
+        p.write_json(&mut json).unwrap();
+        let imported = Profiler::import_json(std::str::from_utf8(&json).unwrap()).unwrap();
+        assert_same_anchors(&imported, &p.results());
+    }
+
+    #[test]
+    fn json_import_reports_where_it_failed() {
+        let err = Profiler::import_json("{\n  \"anchors\": [}").unwrap_err();
+        assert_eq!(err, ParseError::InvalidJson { line: 2, column: 15 });
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_shrinks_many_similar_anchors() {
+        let mut p = Profiler::new();
+        for i in 0..1000 {
+            let id = p.get_anchor_id(&format!("parser::parse_record[{}]", i));
+            p.record_call(id, Duration::from_micros(100));
+        }
+        let mut compressed = Vec::new();
+        p.report_zstd_binary(&mut compressed).unwrap();
+        assert!(compressed.len() * 4 < p.export_compact_binary().len());
+        assert_same_anchors(&Profiler::import_zstd_binary(compressed.as_slice()).unwrap(), &p.results());
+    }
+
+    #[test]
+    fn dhat_json_is_a_version_2_dhat_file() {
+        let mut p = Profiler::new();
+        let ids = [p.get_anchor_id("outer"), p.get_anchor_id("inner")];
+        call_chain(&mut p, &ids);
+        let dhat = p.report_as_dhat_json();
+        assert!(dhat.starts_with("{\"dhatFileVersion\":2,"), "{}", dhat);
+        assert!(dhat.contains("\"ftbl\":[\"[root]\",\"outer\",\"inner\"]"), "{}", dhat);
+        // The innermost frame comes first.
+        assert!(dhat.contains(",\"fs\":[2,1]}"), "{}", dhat);
+        let mut parser = super::JsonParser { text: &dhat, pos: 0 };
+        assert!(parser.value().is_ok());
+        parser.skip_whitespace();
+        assert_eq!(parser.pos, dhat.len());
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn msgpack_round_trips_and_is_smaller_than_json() {
+        let mut p = sample_profiler(10);
+        p.set_anchor_comment("anchor3", "cached");
+        p.add_custom_metric("anchor4", "rows", 42.0);
+        let mut msgpack = Vec::new();
+        p.export_msgpack(&mut msgpack).unwrap();
+        let imported = Profiler::import_msgpack(msgpack.as_slice()).unwrap();
+        assert_same_anchors(&imported, &p.results());
+        assert_eq!(imported.anchors[3].comment.as_deref(), Some("cached"));
+        assert_eq!(imported.anchors[4].custom_metrics["rows"], 42.0);
+        let mut json = Vec::new();
+        p.write_json(&mut json).unwrap();
+        assert!(msgpack.len() < json.len(), "{} {}", msgpack.len(), json.len());
+    }
+
+    #[cfg(feature = "html")]
+    #[test]
+    fn html_report_is_a_page_with_a_table_of_anchors() {
+        let mut p = Profiler::new();
+        let ids = [p.get_anchor_id("outer"), p.get_anchor_id("<inner>")];
+        call_chain(&mut p, &ids);
+        let html = p.report_html();
+        assert!(html.starts_with("<!DOCTYPE html>\n"), "{}", html);
+        assert!(html.contains("<table>"), "{}", html);
+        assert!(html.contains("<td>outer</td>"), "{}", html);
+        // Names are escaped, and each row lists its callers and callees.
+        assert!(html.contains("<td>&lt;inner&gt;</td>"), "{}", html);
+        assert!(html.contains("<b>Calls</b><ul><li>&lt;inner&gt; &times;1 ("), "{}", html);
+    }
+}
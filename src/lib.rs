@@ -1,224 +1,5257 @@
+//! A tiny instrumenting profiler.
+//!
+//! Wrap the code you want to measure in a block; the block records its time
+//! when the guard returned by [`block!`] is dropped:
+//!
+//! ```
+//! fn parse(input: &str) -> usize {
+//!     let _b = pprof::block!();
+//!     input.len()
+//! }
+//!
+//! pprof::init();
+//! parse("hello");
+//! pprof::print();
+//! ```
+//!
+//! Blocks with the same name share one anchor, which makes loop bodies the
+//! intended use case for named blocks: every iteration adds to the same
+//! anchor, so the report shows the number of iterations as the call count
+//! and their summed time.
+//!
+//! ```
+//! # let items = vec![1, 2, 3];
+//! for item in &items {
+//!     let _b = pprof::block!("process");
+//!     // ... work on `item` ...
+//! #   let _ = item;
+//! }
+//! ```
+//!
+//! Blocks are named after the function they are in, plus the given name if
+//! any. In closures, such as one returned as `impl Fn()`, the function's
+//! path ends in `[closure]`; use [`block_named!`] there to pick the whole
+//! name yourself:
+//!
+//! ```
+//! fn make_handler() -> impl Fn(u32) -> u32 {
+//!     |x| {
+//!         let _b = pprof::block_named!("handler");
+//!         x * 2
+//!     }
+//! }
+//! # assert_eq!(make_handler()(2), 4);
+//! ```
+//!
+//! Blocks are safe to start and end from code that runs while the same
+//! thread holds the profiler through [`lock`], such as `Drop` impls or
+//! callbacks invoked by the profiler. Blocks started then are not recorded;
+//! blocks ending then are recorded when the lock is released. Locking
+//! [`PROFILER`] directly bypasses this and deadlocks instead.
+
+// The macros refer to the crate as `pprof`, also in its own tests.
+#[cfg(test)]
+extern crate self as pprof;
+
+#[cfg(feature = "counting-allocator")]
+mod alloc;
+#[cfg(feature = "atomic-stats")]
+mod atomic;
+mod export;
+
+#[cfg(feature = "counting-allocator")]
+pub use alloc::CountingAllocator;
+pub use export::{OutputFormat, ParseError};
 use lazy_static::lazy_static;
-pub use pprof_proc::time;
-use std::sync::Mutex;
-use std::time::Instant;
+pub use pprof_proc::{profile_all, skip_profile, time};
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::fmt::{self, Write as _};
+use std::io::{self, IsTerminal};
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 lazy_static! {
-    pub static ref PROFILER: Mutex<Profiler> = Mutex::new(Profiler::new());
+    pub static ref PROFILER: Mutex<Profiler> = Mutex::new(Profiler::global());
+}
+
+const ANSI_RED: &str = "\x1b[31m";
+const ANSI_YELLOW: &str = "\x1b[33m";
+const ANSI_RESET: &str = "\x1b[0m";
+
+// Global sampling state. It lives outside of `PROFILER` so that blocks which
+// are not sampled never have to take the lock.
+static SAMPLE_RATE: AtomicUsize = AtomicUsize::new(1);
+static SAMPLE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+#[inline]
+fn sample() -> bool {
+    let rate = SAMPLE_RATE.load(Ordering::Relaxed);
+    rate <= 1 || SAMPLE_COUNTER.fetch_add(1, Ordering::Relaxed).is_multiple_of(rate)
+}
+
+// Times `PROFILER` was already locked when we tried to take it. Kept outside
+// the mutex for the same reason as the sampling counter.
+static LOCK_CONTENTION: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    // Whether this thread holds a `ProfilerGuard`.
+    static LOCK_HELD: Cell<bool> = const { Cell::new(false) };
+    // Blocks that ended while this thread held the lock, applied when the
+    // guard is released.
+    static PENDING: RefCell<Vec<EndedBlock>> = const { RefCell::new(Vec::new()) };
+    // User callbacks due while this thread held the lock, run once it is
+    // released so they may use the profiler themselves.
+    static FIRED: RefCell<Vec<Box<dyn FnOnce()>>> = const { RefCell::new(Vec::new()) };
+}
+
+// Callbacks registered with `Profiler::on_threshold_exceeded` and
+// `Profiler::on_anchor_update`. The mutex makes them shareable between
+// clones of a profiler, and keeps a callback from re-entering itself.
+type ThresholdCallback = Arc<Mutex<dyn Fn() + Send>>;
+type AnchorUpdateCallback = Arc<Mutex<dyn Fn(&str, u64, u64) + Send>>;
+
+fn run_fired_callbacks() {
+    for cb in FIRED.with(|fired| fired.take()) {
+        cb();
+    }
+}
+
+fn lock_held() -> bool {
+    LOCK_HELD.with(Cell::get)
+}
+
+fn lock_profiler() -> ProfilerGuard {
+    assert!(!lock_held(), "pprof: the profiler is already locked by this thread");
+    let guard = match PROFILER.try_lock() {
+        Ok(guard) => guard,
+        Err(TryLockError::WouldBlock) => {
+            LOCK_CONTENTION.fetch_add(1, Ordering::Relaxed);
+            PROFILER.lock().unwrap()
+        }
+        Err(TryLockError::Poisoned(e)) => panic!("{}", e),
+    };
+    LOCK_HELD.with(|held| held.set(true));
+    ProfilerGuard { guard: ManuallyDrop::new(guard) }
+}
+
+/// Exclusive access to the global profiler, taken with [`lock`]. Blocks
+/// started on the same thread while it is held are not recorded, and blocks
+/// ending meanwhile are recorded once it is released, instead of
+/// deadlocking.
+pub struct ProfilerGuard {
+    guard: ManuallyDrop<MutexGuard<'static, Profiler>>,
+}
+
+impl Deref for ProfilerGuard {
+    type Target = Profiler;
+
+    fn deref(&self) -> &Profiler {
+        &self.guard
+    }
+}
+
+impl DerefMut for ProfilerGuard {
+    fn deref_mut(&mut self) -> &mut Profiler {
+        &mut self.guard
+    }
+}
+
+impl Drop for ProfilerGuard {
+    fn drop(&mut self) {
+        for block in PENDING.with(|pending| pending.take()) {
+            self.guard.end_block(block);
+        }
+        // SAFETY: `guard` is not touched again after this.
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        LOCK_HELD.with(|held| held.set(false));
+        run_fired_callbacks();
+    }
 }
 
+// `Profiler` is `Send` by auto-trait derivation, and every access to the
+// shared instance goes through the `Mutex`, which makes `PROFILER` `Sync`.
+// Check both at compile time so a future field can't silently break them.
+const _: fn() = || {
+    fn assert_send<T: Send>() {}
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send::<Profiler>();
+    assert_send_sync::<Mutex<Profiler>>();
+    assert_send_sync::<FrozenProfiler>();
+};
+
+#[derive(Clone)]
 pub struct Anchor {
-    name: String,
+    name: Cow<'static, str>,
     elapsed_exclusive: u64,
     elapsed_inclusive: u64,
     calls: usize,
     bytes: usize,
+    // Blocks of this anchor that are currently open.
+    open: usize,
+    is_recursive: bool,
+    // Calls still to be left out of the statistics.
+    warmup_remaining: usize,
+    // Inclusive ticks of every recorded call.
+    #[cfg(feature = "percentiles")]
+    durations: Vec<u64>,
+    // Allocations made directly in this anchor's blocks.
+    #[cfg(feature = "counting-allocator")]
+    alloc_count: u64,
+    #[cfg(feature = "counting-allocator")]
+    alloc_bytes: u64,
+    // When the first recorded call started and the last one ended, in
+    // nanoseconds since the profiler started.
+    #[cfg(feature = "timeline")]
+    first_start_ns: u64,
+    #[cfg(feature = "timeline")]
+    last_end_ns: u64,
+    comment: Option<String>,
+    custom_metrics: BTreeMap<String, f64>,
+    group: Option<String>,
+    // Where the first block of this anchor was started, `""` if unknown.
+    #[cfg(feature = "source-locations")]
+    source_file: &'static str,
+    #[cfg(feature = "source-locations")]
+    source_line: u32,
+    // Every other place a block of this anchor was started, which usually
+    // means two blocks got the same name by accident.
+    #[cfg(feature = "source-locations")]
+    other_source_locations: HashSet<(&'static str, u32)>,
 }
 
 impl Anchor {
     pub fn new(name: &str) -> Self {
+        Self::with_name(Cow::Owned(name.to_string()))
+    }
+
+    pub fn from_static(name: &'static str) -> Self {
+        Self::with_name(Cow::Borrowed(name))
+    }
+
+    // Adds the statistics of `other` to this anchor's, for `compact`.
+    fn absorb(&mut self, other: &Anchor) {
+        #[cfg(feature = "timeline")]
+        if other.calls != 0 {
+            if self.calls != 0 {
+                self.first_start_ns = self.first_start_ns.min(other.first_start_ns);
+            } else {
+                self.first_start_ns = other.first_start_ns;
+            }
+            self.last_end_ns = self.last_end_ns.max(other.last_end_ns);
+        }
+        self.elapsed_exclusive = self.elapsed_exclusive.wrapping_add(other.elapsed_exclusive);
+        self.elapsed_inclusive += other.elapsed_inclusive;
+        self.calls += other.calls;
+        self.bytes += other.bytes;
+        self.is_recursive |= other.is_recursive;
+        #[cfg(feature = "percentiles")]
+        self.durations.extend_from_slice(&other.durations);
+        #[cfg(feature = "counting-allocator")]
+        {
+            self.alloc_count = self.alloc_count.wrapping_add(other.alloc_count);
+            self.alloc_bytes = self.alloc_bytes.wrapping_add(other.alloc_bytes);
+        }
+        if self.comment.is_none() {
+            self.comment.clone_from(&other.comment);
+        }
+        for (key, &value) in &other.custom_metrics {
+            self.custom_metrics.entry(key.clone()).or_insert(value);
+        }
+        if self.group.is_none() {
+            self.group.clone_from(&other.group);
+        }
+        #[cfg(feature = "source-locations")]
+        {
+            let first = (self.source_file, self.source_line);
+            let others = std::iter::once((other.source_file, other.source_line))
+                .chain(other.other_source_locations.iter().copied())
+                .filter(|&location| location != first && !location.0.is_empty());
+            self.other_source_locations.extend(others);
+        }
+    }
+
+    fn clear_stats(&mut self) {
+        self.elapsed_exclusive = 0;
+        self.elapsed_inclusive = 0;
+        self.calls = 0;
+        self.bytes = 0;
+        self.is_recursive = false;
+        #[cfg(feature = "percentiles")]
+        self.durations.clear();
+        #[cfg(feature = "counting-allocator")]
+        {
+            self.alloc_count = 0;
+            self.alloc_bytes = 0;
+        }
+        #[cfg(feature = "timeline")]
+        {
+            self.first_start_ns = 0;
+            self.last_end_ns = 0;
+        }
+        self.custom_metrics.clear();
+    }
+
+    fn result(&self, freq: f64) -> AnchorResult {
+        AnchorResult {
+            name: self.name.to_string(),
+            calls: self.calls,
+            bytes: self.bytes,
+            exclusive_ms: self.elapsed_exclusive as f64 / freq * 1000.0,
+            inclusive_ms: self.elapsed_inclusive as f64 / freq * 1000.0,
+            ticks_exclusive: self.elapsed_exclusive,
+            ticks_inclusive: self.elapsed_inclusive,
+            #[cfg(feature = "timeline")]
+            first_start_ns: self.first_start_ns,
+            #[cfg(feature = "timeline")]
+            last_end_ns: self.last_end_ns,
+            comment: self.comment.clone(),
+            custom_metrics: self.custom_metrics.clone(),
+        }
+    }
+
+    fn with_name(name: Cow<'static, str>) -> Self {
         Self {
-            name: name.to_string(),
+            name,
             elapsed_exclusive: 0,
             elapsed_inclusive: 0,
             calls: 0,
             bytes: 0,
+            open: 0,
+            is_recursive: false,
+            warmup_remaining: 0,
+            #[cfg(feature = "percentiles")]
+            durations: Vec::new(),
+            #[cfg(feature = "counting-allocator")]
+            alloc_count: 0,
+            #[cfg(feature = "counting-allocator")]
+            alloc_bytes: 0,
+            #[cfg(feature = "timeline")]
+            first_start_ns: 0,
+            #[cfg(feature = "timeline")]
+            last_end_ns: 0,
+            comment: None,
+            custom_metrics: BTreeMap::new(),
+            group: None,
+            #[cfg(feature = "source-locations")]
+            source_file: "",
+            #[cfg(feature = "source-locations")]
+            source_line: 0,
+            #[cfg(feature = "source-locations")]
+            other_source_locations: HashSet::new(),
+        }
+    }
+}
+
+/// A snapshot of one anchor's statistics, with times in milliseconds.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnchorResult {
+    pub name: String,
+    pub calls: usize,
+    pub bytes: usize,
+    pub exclusive_ms: f64,
+    pub inclusive_ms: f64,
+    ticks_exclusive: u64,
+    ticks_inclusive: u64,
+    /// When the first call started and the last call ended, in nanoseconds
+    /// since the profiler started. Zero for imported profiles.
+    #[cfg(feature = "timeline")]
+    pub first_start_ns: u64,
+    #[cfg(feature = "timeline")]
+    pub last_end_ns: u64,
+    /// Set with [`Profiler::set_anchor_comment`].
+    pub comment: Option<String>,
+    /// Set with [`Profiler::add_custom_metric`].
+    pub custom_metrics: BTreeMap<String, f64>,
+}
+
+impl AnchorResult {
+    /// The unconverted exclusive time. With the `rdtsc` feature this is in
+    /// TSC ticks, otherwise in nanoseconds. Results read back with
+    /// [`Profiler::import_binary`] are always in nanoseconds.
+    pub fn raw_ticks_exclusive(&self) -> u64 {
+        self.ticks_exclusive
+    }
+
+    /// The unconverted inclusive time, in the same unit as
+    /// [`AnchorResult::raw_ticks_exclusive`].
+    pub fn raw_ticks_inclusive(&self) -> u64 {
+        self.ticks_inclusive
+    }
+
+    /// The processed bytes with a readable unit, e.g. `"1.95 KB"`.
+    pub fn display_bytes(&self) -> String {
+        format_bytes(self.bytes as u64)
+    }
+}
+
+/// A read-only copy of a profiler's statistics, taken with
+/// [`Profiler::freeze`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct FrozenProfiler {
+    results: ProfileResults,
+    total_ms: f64,
+    max_depth: usize,
+}
+
+impl FrozenProfiler {
+    pub fn anchors_iter(&self) -> impl Iterator<Item = &AnchorResult> {
+        self.results.anchors.iter()
+    }
+
+    pub fn find_anchor(&self, name: &str) -> Option<&AnchorResult> {
+        self.results.anchors.iter().find(|a| a.name == name)
+    }
+
+    pub fn results(&self) -> &ProfileResults {
+        &self.results
+    }
+
+    /// Time since the profiler was last reset, as of the freeze.
+    pub fn total_ms(&self) -> f64 {
+        self.total_ms
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+}
+
+/// A failed [`Profiler::assert_called`] check.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AssertionError {
+    pub anchor_name: String,
+    pub expected_calls: usize,
+    pub actual_calls: usize,
+}
+
+impl fmt::Display for AssertionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "expected {} to be called {} times, but it was called {} times",
+            self.anchor_name, self.expected_calls, self.actual_calls,
+        )
+    }
+}
+
+impl std::error::Error for AssertionError {}
+
+/// A block still open when [`Profiler::assert_no_leaked_blocks`] ran.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LeakedBlockError {
+    /// The innermost open block's anchor.
+    pub anchor_name: String,
+}
+
+impl fmt::Display for LeakedBlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "block of {} is still open", self.anchor_name)
+    }
+}
+
+impl std::error::Error for LeakedBlockError {}
+
+/// An anchor that took longer than its budget, see
+/// [`Profiler::report_timing_budget`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct BudgetViolation {
+    pub name: String,
+    pub budget_ms: f64,
+    pub actual_ms: f64,
+}
+
+/// A snapshot of all anchors of a profiler, root sentinel excluded.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProfileResults {
+    pub anchors: Vec<AnchorResult>,
+}
+
+#[derive(Clone, Default)]
+struct Edge {
+    calls: usize,
+    elapsed_inclusive: u64,
+}
+
+/// What report percentages are relative to, see
+/// [`Profiler::set_percentage_base`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PercentageBase {
+    /// The wall-clock time since the profiler started. Shows how much of the
+    /// whole run an anchor took, but the percentages of a program that is
+    /// only partly instrumented add up to well below 100%.
+    #[default]
+    WallClock,
+    /// The time spent in top-level blocks. Shows each anchor's share of the
+    /// instrumented code only; uninstrumented time is left out entirely.
+    InstrumentedTime,
+}
+
+/// The order of [`Profiler::print_sorted_by`] and
+/// [`Profiler::print_sorted_by_multi`]. Anchors that tie on every key are
+/// sorted by name, so reports stay diffable when timings jitter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortKey {
+    SelfTimeDesc,
+    TotalTimeDesc,
+    CallsDesc,
+    NameAsc,
+}
+
+impl SortKey {
+    fn compare(self, a: &Anchor, b: &Anchor) -> std::cmp::Ordering {
+        match self {
+            SortKey::SelfTimeDesc => b.elapsed_exclusive.cmp(&a.elapsed_exclusive),
+            SortKey::TotalTimeDesc => b.elapsed_inclusive.cmp(&a.elapsed_inclusive),
+            SortKey::CallsDesc => b.calls.cmp(&a.calls),
+            SortKey::NameAsc => a.name.cmp(&b.name),
         }
     }
 }
 
+/// The clock blocks are timed with, see [`Profiler::set_clock_source`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClockSource {
+    /// `std::time::Instant`, in nanoseconds.
+    Instant,
+    /// The CPU's time stamp counter. Cheaper to read than `Instant`, but
+    /// only available with the `rdtsc` feature.
+    Rdtsc,
+    /// The operating system's monotonic clock. `Instant` already reads it
+    /// on every supported platform, so this is the same as `Instant`.
+    Monotonic,
+}
+
+/// Where [`Profiler::print`] and the other `print_*` methods write, see
+/// [`Profiler::set_output_target`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputTarget {
+    #[default]
+    Stdout,
+    Stderr,
+    /// One `log` record per report at the given level.
+    #[cfg(feature = "log")]
+    Log(log::Level),
+}
+
+#[derive(Clone)]
 pub struct Profiler {
     anchors: Vec<Anchor>,
+    // Keyed by (parent anchor id, child anchor id).
+    edges: BTreeMap<(usize, usize), Edge>,
     start: Instant,
+    // `start` as wall-clock time, for report IDs.
+    started_at: SystemTime,
+    // Where total time stops, for snapshots from `clone_and_reset`.
+    end: Option<Instant>,
+    last_print_time: Option<Instant>,
     parent_id: usize,
+    current_depth: usize,
+    max_depth: usize,
+    max_name_length: usize,
+    precision: usize,
+    color: Option<bool>,
+    slow_percentile: Option<f64>,
+    wall_clock_mode: bool,
+    // Blocks completed since the last reset, for auto-printing.
+    drop_count: usize,
+    auto_print_interval: usize,
+    warmup_calls: usize,
+    // Display names, keyed by anchor name.
+    aliases: HashMap<String, String>,
+    log_prefix: Option<String>,
+    // Replacements for the default header line and the empty footer.
+    header: Option<String>,
+    footer: Option<String>,
+    // Results as of the last `print()`, when in delta mode.
+    delta_baseline: Option<Vec<AnchorResult>>,
+    enabled: bool,
+    hide_passthrough: bool,
+    nesting_error_count: usize,
+    max_anchors: Option<usize>,
+    // Anchors not created because of `max_anchors`, since the last reset.
+    overflow_count: usize,
+    bar_width: usize,
+    percentage_base: PercentageBase,
+    output_target: OutputTarget,
+    // Anchor name, self time threshold in milliseconds, callback.
+    threshold_callbacks: Vec<(String, f64, ThresholdCallback)>,
+    anchor_update_callback: Option<AnchorUpdateCallback>,
+    #[cfg(feature = "source-locations")]
+    include_source_locations: bool,
+    #[cfg(feature = "atomic-stats")]
+    atomic_stats: atomic::AtomicStatsTable,
 }
 
 impl Profiler {
     pub fn new() -> Self {
-        let mut anchors = Vec::new();
-        anchors.push(Anchor::new(""));
+        Self::with_capacity(8)
+    }
+
+    /// Creates a profiler with room for `n` anchors besides the root
+    /// sentinel, so registering up to `n` anchors never reallocates.
+    pub fn with_capacity(n: usize) -> Self {
+        // Ending a block can convert ticks to time, e.g. for the timeline, so
+        // calibrate before any block is timed rather than inside one.
+        #[cfg(feature = "rdtsc")]
+        get_duration_freq();
+        let mut anchors = Vec::with_capacity(n + 1);
+        anchors.push(Anchor::from_static(""));
         Self {
             anchors,
+            edges: BTreeMap::new(),
             start: Instant::now(),
+            started_at: SystemTime::now(),
+            end: None,
+            last_print_time: None,
             parent_id: 0,
+            current_depth: 0,
+            max_depth: 0,
+            max_name_length: usize::MAX,
+            precision: 4,
+            color: None,
+            slow_percentile: Some(95.0),
+            wall_clock_mode: false,
+            drop_count: 0,
+            auto_print_interval: 0,
+            warmup_calls: 0,
+            aliases: HashMap::new(),
+            log_prefix: None,
+            header: None,
+            footer: None,
+            delta_baseline: None,
+            enabled: true,
+            hide_passthrough: false,
+            nesting_error_count: 0,
+            max_anchors: None,
+            overflow_count: 0,
+            bar_width: 20,
+            percentage_base: PercentageBase::WallClock,
+            output_target: OutputTarget::Stdout,
+            threshold_callbacks: Vec::new(),
+            anchor_update_callback: None,
+            #[cfg(feature = "source-locations")]
+            include_source_locations: false,
+            #[cfg(feature = "atomic-stats")]
+            atomic_stats: Default::default(),
+        }
+    }
+
+    // The instance behind `PROFILER`.
+    fn global() -> Self {
+        #[allow(unused_mut)]
+        let mut profiler = Self::with_capacity(64);
+        #[cfg(feature = "atomic-stats")]
+        {
+            profiler.atomic_stats = atomic::AtomicStatsTable::global();
         }
+        profiler
+    }
+
+    /// The statistics of the global profiler's anchor named `name` as of its
+    /// last completed block, read without locking [`PROFILER`], so a
+    /// monitoring thread never holds up the profiled code. Times are in
+    /// nanoseconds. `None` for anchors without a completed block since the
+    /// program started.
+    #[cfg(feature = "atomic-stats")]
+    pub fn read_anchor_atomic(name: &str) -> Option<AnchorResult> {
+        atomic::read(name)
     }
 
     pub fn get_anchor_id(&mut self, name: &str) -> usize {
-        let i = if let Some(i) = self.anchors.iter().position(|n| n.name.as_str() == name) {
+        if let Some(i) = self.find_anchor_id(name) {
             i
         } else {
-            self.anchors.push(Anchor::new(name));
-            self.anchors.len() - 1
-        };
-        i
+            self.push_anchor(Anchor::new(name))
+        }
     }
 
-    pub fn print(&mut self) {
-        let total_duration = self.start.elapsed().as_nanos() as f64 / 1_000_000_000.0;
-        let freq = get_duration_freq();
-        println!("--- PProf Results ---");
-        println!("Total time: {:.4}ms", total_duration * 1000.0);
-        for anchor in &self.anchors {
-            if anchor.elapsed_inclusive != 0 {
-                let elapsed = anchor.elapsed_inclusive as f64 / freq;
-                let self_elapsed = anchor.elapsed_exclusive as f64 / freq;
-                let elapsed_percentage = elapsed as f64 / total_duration * 100.0;
-                let self_elapsed_percentage = self_elapsed as f64 / total_duration * 100.0;
-
-                let throughput_str = if anchor.bytes != 0 {
-                    let mb = (1024 * 1024) as f64;
-                    let gb = (1024 * 1024 * 1024) as f64;
-                    format!(" throughput={:.4} MB at {:.4} GB/s", anchor.bytes as f64 / mb, anchor.bytes as f64 / gb / elapsed)
-                } else {
-                    String::new()
-                };
+    /// Like [`Profiler::get_anchor_id`], but a newly created anchor borrows
+    /// `name` instead of copying it.
+    pub fn get_static_anchor_id(&mut self, name: &'static str) -> usize {
+        if let Some(i) = self.find_anchor_id(name) {
+            i
+        } else {
+            self.push_anchor(Anchor::from_static(name))
+        }
+    }
 
-                println!(
-                    "{}[{}] - total={:.4}ms ({:.4}%) self={:.4}ms ({:.4}%){}",
-                    anchor.name,
-                    anchor.calls,
-                    elapsed * 1000.0,
-                    elapsed_percentage,
-                    self_elapsed * 1000.0,
-                    self_elapsed_percentage,
-                    throughput_str,
-                );
-            }
+    /// The statistics of the anchor with the given ID, as returned by
+    /// [`Profiler::get_anchor_id`], without a name lookup. `None` for IDs
+    /// that don't belong to an anchor.
+    pub fn anchor_at_index(&self, id: usize) -> Option<AnchorResult> {
+        let freq = self.freq();
+        self.anchors.get(id).filter(|_| id != 0).map(|anchor| anchor.result(freq))
+    }
+
+    /// The name of the anchor with the given ID, see
+    /// [`Profiler::anchor_at_index`].
+    pub fn anchor_name_at_index(&self, id: usize) -> Option<&str> {
+        self.anchors.get(id).filter(|_| id != 0).map(|anchor| anchor.name.as_ref())
+    }
+
+    fn push_anchor(&mut self, mut anchor: Anchor) -> usize {
+        if self.max_anchors.is_some_and(|max| self.anchors.len() > max) {
+            self.overflow_count += 1;
+            return 0;
         }
+        anchor.warmup_remaining = self.warmup_calls;
+        self.anchors.push(anchor);
+        self.anchors.len() - 1
     }
 
-    pub fn add_bytes(&mut self, anchor_id: usize, bytes: usize) {
-        self.anchors[anchor_id].bytes += bytes;
+    fn find_anchor_id(&self, name: &str) -> Option<usize> {
+        self.anchors.iter().position(|n| n.name == name)
     }
-}
 
-#[cfg(feature = "rdtsc")]
-macro_rules! get_cpu_timer {
-    () => {{
-        unsafe {
-            core::arch::x86_64::_rdtsc()
+    /// Checks that the anchor named `anchor_name` completed exactly
+    /// `expected_calls` blocks. An anchor that was never registered counts
+    /// as having no calls.
+    pub fn assert_called(&self, anchor_name: &str, expected_calls: usize) -> Result<(), AssertionError> {
+        let actual_calls = self.anchors[1..].iter().find(|a| a.name == anchor_name).map_or(0, |a| a.calls);
+        if actual_calls == expected_calls {
+            Ok(())
+        } else {
+            Err(AssertionError {
+                anchor_name: anchor_name.to_string(),
+                expected_calls,
+                actual_calls,
+            })
         }
-    }}
-}
+    }
 
-#[cfg(not(feature = "rdtsc"))]
-pub struct Block {
-    start: Instant,
-    anchor_id: usize,
-    parent_id: usize,
-    old_elapsed_inclusive: u64,
-}
+    /// Checks that no block is open, i.e. the statistics are complete.
+    pub fn assert_no_leaked_blocks(&self) -> Result<(), LeakedBlockError> {
+        if self.parent_id == 0 {
+            Ok(())
+        } else {
+            Err(LeakedBlockError { anchor_name: self.anchors[self.parent_id].name.to_string() })
+        }
+    }
 
-#[cfg(feature = "rdtsc")]
-pub struct Block {
-    start: u64,
-    anchor_id: usize,
-    parent_id: usize,
-    old_elapsed_inclusive: u64,
-}
+    /// Checks the total time of every anchor named in `budget_map` against
+    /// its budget in milliseconds and returns the anchors over budget,
+    /// sorted by name. Anchors that never ran are within any budget.
+    pub fn report_timing_budget(&self, budget_map: &HashMap<String, f64>) -> Vec<BudgetViolation> {
+        let freq = self.freq();
+        let mut violations: Vec<BudgetViolation> = budget_map
+            .iter()
+            .filter_map(|(name, &budget_ms)| {
+                let anchor = self.anchors[1..].iter().find(|a| a.name == name.as_str())?;
+                let actual_ms = anchor.elapsed_inclusive as f64 / freq * 1000.0;
+                (actual_ms > budget_ms).then(|| BudgetViolation {
+                    name: name.clone(),
+                    budget_ms,
+                    actual_ms,
+                })
+            })
+            .collect();
+        violations.sort_by(|a, b| a.name.cmp(&b.name));
+        violations
+    }
 
-impl Block {
-    #[cfg(not(feature = "rdtsc"))]
-    pub fn new(anchor_id: usize, parent_id: usize, old_elapsed_inclusive: u64) -> Self {
-        Self {
-            start: Instant::now(),
-            anchor_id,
-            parent_id,
-            old_elapsed_inclusive,
+    /// The number of registered anchors, not counting the root sentinel.
+    pub fn anchor_count(&self) -> usize {
+        self.anchors.len() - 1
+    }
+
+    /// Whether an anchor named `name` has been registered, whether or not it
+    /// completed any call.
+    pub fn anchor_exists(&self, name: &str) -> bool {
+        self.anchors[1..].iter().any(|a| a.name == name)
+    }
+
+    /// The number of anchors that have completed at least one call.
+    pub fn active_anchor_count(&self) -> usize {
+        self.anchors[1..].iter().filter(|a| a.calls != 0).count()
+    }
+
+    /// The fraction (0.0 to 1.0) of the time since the profiler started that
+    /// was spent inside at least one block. Blocks on a thread never overlap
+    /// at the top level, so this is the time covered by top-level blocks.
+    pub fn wall_clock_coverage(&self) -> f64 {
+        let total_duration = self.elapsed().as_secs_f64();
+        if total_duration == 0.0 {
+            return 0.0;
         }
+        self.instrumented_ticks() as f64 / self.freq() / total_duration
     }
 
-    #[cfg(feature = "rdtsc")]
-    pub fn new(anchor_id: usize, parent_id: usize, old_elapsed_inclusive: u64) -> Self {
-        Self {
-            start: get_cpu_timer!(),
-            anchor_id,
-            parent_id,
-            old_elapsed_inclusive,
+    /// The deepest nesting of blocks observed so far; a lone top-level block
+    /// has depth 1.
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    /// Sum of all anchors' exclusive times. Once every block has been
+    /// dropped this equals the time spent inside top-level blocks.
+    pub fn total_exclusive_ms(&self) -> f64 {
+        self.total_exclusive_ticks() as f64 / self.freq() * 1000.0
+    }
+
+    /// Sum of all anchors' inclusive times. Nested anchors are counted once
+    /// per level, so this can exceed the total elapsed time.
+    pub fn total_inclusive_ms(&self) -> f64 {
+        let ticks: u64 = self.anchors[1..].iter().map(|a| a.elapsed_inclusive).sum();
+        ticks as f64 / self.freq() * 1000.0
+    }
+
+    /// Bytes added to all anchors, see [`Profiler::add_bytes`].
+    pub fn bytes_total(&self) -> u64 {
+        self.anchors[1..].iter().map(|a| a.bytes as u64).sum()
+    }
+
+    /// [`Profiler::bytes_total`] over the time since the profiler started,
+    /// in GB/s, to check that the profile covered all the expected I/O. 0.0
+    /// when no time has passed yet.
+    pub fn bytes_throughput_gb_s(&self) -> f64 {
+        let seconds = self.elapsed().as_secs_f64();
+        if seconds == 0.0 {
+            return 0.0;
         }
+        self.bytes_total() as f64 / (1024 * 1024 * 1024) as f64 / seconds
     }
 
-    #[cfg(not(feature = "rdtsc"))]
-    pub fn elapsed(&self) -> u64 {
-        self.start.elapsed().as_nanos() as u64
+    /// How evenly self time is spread over the anchors that have any, as a
+    /// single number to track in CI: 0.0 when it is all in one anchor (one
+    /// obvious thing to optimize), 1.0 when every anchor has the same. This
+    /// is one minus the Gini coefficient of the self times, normalized so
+    /// that both ends are reachable with any number of anchors.
+    pub fn compute_efficiency_score(&self) -> f64 {
+        let mut times: Vec<f64> = self.anchors[1..]
+            .iter()
+            .filter(|a| a.elapsed_exclusive != 0 && a.elapsed_exclusive <= i64::MAX as u64)
+            .map(|a| a.elapsed_exclusive as f64)
+            .collect();
+        if times.len() < 2 {
+            return 0.0;
+        }
+        let n = times.len() as f64;
+        let sum: f64 = times.iter().sum();
+        times.sort_by(f64::total_cmp);
+        let weighted: f64 = times.iter().enumerate().map(|(i, &t)| (i + 1) as f64 * t).sum();
+        let gini = 2.0 * weighted / (n * sum) - (n + 1.0) / n;
+        (1.0 - gini * n / (n - 1.0)).clamp(0.0, 1.0)
     }
 
-    #[cfg(feature = "rdtsc")]
-    pub fn elapsed(&self) -> u64 {
-        get_cpu_timer!() - self.start
+    /// The share of all exclusive time spent in the anchor with the most
+    /// of it: near 1.0 when one function dominates, lower when time is
+    /// spread out. 0.0 when nothing has been recorded.
+    pub fn hot_path_ratio(&self) -> f64 {
+        let total = self.total_exclusive_ticks();
+        match self.hottest_anchor() {
+            Some(anchor) if total != 0 => anchor.elapsed_exclusive as f64 / total as f64,
+            _ => 0.0,
+        }
     }
 
-    pub fn from_id(id: usize) -> Self {
-        let mut p = PROFILER.lock().unwrap();
-        let parent_id = p.parent_id;
-        let old_elapsed_inclusive = p.anchors[id].elapsed_inclusive;
-        p.parent_id = id;
-        Self::new(id, parent_id, old_elapsed_inclusive)
+    /// The name of the anchor [`Profiler::hot_path_ratio`] refers to.
+    pub fn hot_path_name(&self) -> Option<&str> {
+        self.hottest_anchor().map(|anchor| anchor.name.as_ref())
     }
-}
 
-impl Drop for Block {
-    fn drop(&mut self) {
-        let elapsed = self.elapsed();
-        let mut p = PROFILER.lock().unwrap();
-        p.parent_id = self.parent_id;
-        p.anchors[self.parent_id].elapsed_exclusive -= elapsed;
-        p.anchors[self.anchor_id].elapsed_exclusive += elapsed;
-        p.anchors[self.anchor_id].elapsed_inclusive = self.old_elapsed_inclusive + elapsed;
-        p.anchors[self.anchor_id].calls += 1;
+    fn hottest_anchor(&self) -> Option<&Anchor> {
+        self.anchors[1..].iter().filter(|a| a.calls != 0).max_by_key(|a| a.elapsed_exclusive)
     }
-}
 
-#[cfg(not(feature = "rdtsc"))]
-fn get_duration_freq() -> f64 {
-    1000_000_000.0
-}
+    fn total_exclusive_ticks(&self) -> u64 {
+        self.anchors[1..].iter().fold(0, |sum, a| sum.wrapping_add(a.elapsed_exclusive))
+    }
 
-#[cfg(feature = "rdtsc")]
-fn get_duration_freq() -> f64 {
-    let start = get_cpu_timer!();
-    std::thread::sleep(std::time::Duration::from_millis(100));
-    let end = get_cpu_timer!();
-    (end - start) as f64 * 10.0
-}
+    // Every top-level block subtracts its elapsed time from the root
+    // sentinel, so the root's (wrapped) exclusive time is the negated sum of
+    // all top-level inclusive times.
+    fn instrumented_ticks(&self) -> u64 {
+        self.anchors[0].elapsed_exclusive.wrapping_neg()
+    }
+
+    pub fn results(&self) -> ProfileResults {
+        let freq = self.freq();
+        ProfileResults {
+            anchors: self.anchors.iter().skip(1).map(|a| a.result(freq)).collect(),
+        }
+    }
 
+    /// Yields every anchor's statistics, most self time first. The sort order
+    /// is computed on the stack for up to 64 anchors, so only the yielded
+    /// results allocate.
+    pub fn iter_sorted_by_self_time(&self) -> impl Iterator<Item = AnchorResult> + '_ {
+        let freq = self.freq();
+        let count = self.anchors.len() - 1;
+        let key = |&id: &usize| (std::cmp::Reverse(self.anchors[id].elapsed_exclusive), id);
+        let ids = if count <= INLINE_SORT_LEN {
+            let mut ids = [0; INLINE_SORT_LEN];
+            for (slot, id) in ids.iter_mut().zip(1..=count) {
+                *slot = id;
+            }
+            // Unstable sorts don't allocate; the ID in the key keeps ties in
+            // registration order.
+            ids[..count].sort_unstable_by_key(key);
+            SortedIds::Inline(ids, 0..count)
+        } else {
+            let mut ids: Vec<usize> = (1..=count).collect();
+            ids.sort_unstable_by_key(key);
+            SortedIds::Heap(ids.into_iter())
+        };
+        ids.map(move |id| self.anchors[id].result(freq))
+    }
 
-#[macro_export]
-macro_rules! fn_name {
-    () => {{
-        fn f() {}
-        fn type_name_of<T>(_: T) -> &'static str {
-            std::any::type_name::<T>()
+    /// Like [`Profiler::iter_sorted_by_self_time`], but only orders as many
+    /// anchors as are taken: the first call to `next` puts the anchors in a
+    /// heap, and each call after that pops the next one off it. Cheaper
+    /// when only the top few anchors are needed, e.g.
+    /// `iter_by_self_time().take(3)`.
+    pub fn iter_by_self_time(&self) -> impl Iterator<Item = AnchorResult> + '_ {
+        BySelfTime { profiler: self, heap: None }
+    }
+
+    /// Follows the call graph from the top level down, always into the
+    /// callee that took the most time, and returns the anchors on that path
+    /// in top-down order. This is the chain worth optimizing first.
+    pub fn compute_critical_path(&self) -> Vec<AnchorResult> {
+        let freq = self.freq();
+        let mut path = Vec::new();
+        let mut visited = vec![false; self.anchors.len()];
+        let mut node = 0;
+        while let Some((&(_, child), _)) = self
+            .edges
+            .range((node, 0)..(node + 1, 0))
+            .filter(|(&(_, child), _)| child != 0 && !visited[child])
+            .max_by_key(|(_, edge)| edge.elapsed_inclusive)
+        {
+            visited[child] = true;
+            path.push(self.anchors[child].result(freq));
+            node = child;
         }
-        type_name_of(f).strip_suffix("::f").unwrap()
-    }};
-}
+        path
+    }
 
-#[macro_export]
-macro_rules! block {
-    () => {{
-        let name = pprof::fn_name!();
-        let id = pprof::PROFILER.lock().unwrap().get_anchor_id(&name);
-        pprof::Block::from_id(id)
-    }};
-    ($name:expr) => {{
-        let name = format!("{}[{}]", pprof::fn_name!(), $name);
-        let id = pprof::PROFILER.lock().unwrap().get_anchor_id(&name);
-        pprof::Block::from_id(id)
-    }};
-    ($name:expr, $bytes:expr) => {{
-        let name = format!("{}[{}]", pprof::fn_name!(), $name);
-        let id = pprof::PROFILER.lock().unwrap().get_anchor_id(&name);
-        pprof::PROFILER.lock().unwrap().add_bytes(id, $bytes);
-        pprof::Block::from_id(id)
-    }}
-}
+    /// Copies the current statistics into a [`FrozenProfiler`], which can
+    /// then be read from any thread without touching the global lock.
+    pub fn freeze(&self) -> FrozenProfiler {
+        FrozenProfiler {
+            results: self.results(),
+            total_ms: self.elapsed().as_secs_f64() * 1000.0,
+            max_depth: self.max_depth,
+        }
+    }
 
-pub fn init() {
-    PROFILER.lock().unwrap().start = Instant::now();
-}
+    /// Truncates anchor names longer than `max` characters in printed
+    /// output, ending them with `…`. Stored names are not affected.
+    pub fn set_max_name_length(&mut self, max: usize) {
+        self.max_name_length = max;
+    }
 
-pub fn print() {
-    PROFILER.lock().unwrap().print();
+    /// Records only one out of every `n` blocks, counted across all anchors.
+    /// Skipped blocks are not timed at all and their time stays with the
+    /// enclosing block. `n <= 1` records every block.
+    pub fn set_global_sample_rate(n: usize) {
+        SAMPLE_RATE.store(n, Ordering::Relaxed);
+    }
+
+    /// Sets the number of decimal places used for times, percentages and
+    /// throughput in printed output. Defaults to 4.
+    pub fn set_output_precision(&mut self, decimal_places: usize) {
+        self.precision = decimal_places;
+    }
+
+    /// Forces colored output on or off. By default, hot anchors are colored
+    /// only when the output target (see [`Profiler::set_output_target`]) is
+    /// a terminal. Reports sent to the `log` crate are never colored.
+    pub fn set_color(&mut self, enabled: bool) {
+        self.color = Some(enabled);
+    }
+
+    /// Sets the percentile of self times above which anchors are marked
+    /// `[SLOW]` (and colored red, with color on) in reports, 95 by default.
+    /// `None` turns the badge off.
+    pub fn set_slow_percentile(&mut self, percentile: Option<f64>) {
+        self.slow_percentile = percentile;
+    }
+
+    // The self time, in ticks, above which an anchor is marked `[SLOW]`: the
+    // nearest-rank `slow_percentile` of the self times of the anchors with
+    // calls.
+    fn slow_threshold(&self) -> Option<u64> {
+        let percentile = self.slow_percentile?;
+        let mut self_times: Vec<u64> =
+            self.anchors[1..].iter().filter(|a| a.calls != 0).map(|a| a.elapsed_exclusive).collect();
+        if self_times.is_empty() {
+            return None;
+        }
+        self_times.sort_unstable();
+        let rank = ((percentile / 100.0 * self_times.len() as f64).ceil() as usize).clamp(1, self_times.len());
+        Some(self_times[rank - 1])
+    }
+
+    fn use_color(&self) -> bool {
+        match self.output_target {
+            OutputTarget::Stdout => self.color.unwrap_or_else(|| io::stdout().is_terminal()),
+            OutputTarget::Stderr => self.color.unwrap_or_else(|| io::stderr().is_terminal()),
+            #[cfg(feature = "log")]
+            OutputTarget::Log(_) => false,
+        }
+    }
+
+    // Reports are colored for the output target; this drops the colors from
+    // `report` if it goes to a stream that shouldn't get them instead.
+    fn uncolored_unless<'a>(&self, report: &'a str, stream_is_terminal: bool) -> Cow<'a, str> {
+        if self.use_color() && !self.color.unwrap_or(stream_is_terminal) {
+            Cow::Owned(strip_colors(report))
+        } else {
+            Cow::Borrowed(report)
+        }
+    }
+
+    /// Times blocks with `Instant` instead of the TSC even when the `rdtsc`
+    /// feature is enabled, for machines where frequency scaling makes the
+    /// TSC unreliable. Without `rdtsc` blocks always use `Instant` and this
+    /// has no effect.
+    ///
+    /// # Note
+    ///
+    /// The two clocks count in different units, so switching modes discards
+    /// all collected statistics like [`Profiler::reset`]. While blocks are
+    /// open the switch is refused with a warning and the statistics are
+    /// kept, since those blocks would end on the other clock.
+    pub fn set_wall_clock_mode(&mut self, enabled: bool) {
+        if cfg!(feature = "rdtsc") && enabled != self.wall_clock_mode {
+            if self.current_depth != 0 {
+                eprintln!("pprof warning: not switching clocks with {} blocks open", self.current_depth);
+                return;
+            }
+            self.wall_clock_mode = enabled;
+            self.reset();
+        }
+    }
+
+    /// Picks the clock blocks are timed with at runtime, e.g. to compare the
+    /// overhead of both on the same build. Like
+    /// [`Profiler::set_wall_clock_mode`], which this is built on, switching
+    /// clocks resets the collected statistics and is refused while blocks
+    /// are open. Without the `rdtsc` feature
+    /// [`ClockSource::Rdtsc`] prints a warning and keeps using `Instant`.
+    pub fn set_clock_source(&mut self, source: ClockSource) {
+        if source == ClockSource::Rdtsc && cfg!(not(feature = "rdtsc")) {
+            eprintln!("pprof warning: built without the rdtsc feature, timing with Instant instead");
+        }
+        self.set_wall_clock_mode(source != ClockSource::Rdtsc);
+    }
+
+    /// The clock blocks are currently timed with. Reports
+    /// [`ClockSource::Instant`] for [`ClockSource::Monotonic`].
+    pub fn clock_source(&self) -> ClockSource {
+        if self.is_wall_clock_mode() {
+            ClockSource::Instant
+        } else {
+            ClockSource::Rdtsc
+        }
+    }
+
+    /// Prints the report to stderr after every `n` completed blocks, across
+    /// all anchors. `n = 0` turns this off.
+    pub fn set_auto_print_interval(&mut self, n: usize) {
+        self.auto_print_interval = n;
+    }
+
+    /// Turns recording on or off at runtime. Blocks started while disabled
+    /// are not recorded, and their time stays with the enclosing block.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Hides anchors from reports that spend next to no time themselves
+    /// (less than 0.1% of the total) and only wrap their children. They
+    /// are still recorded and still count as parents.
+    pub fn set_hide_passthrough(&mut self, hide: bool) {
+        self.hide_passthrough = hide;
+    }
+
+    /// Sets the width of the bar at the end of each report line, which shows
+    /// the anchor's self time relative to the anchor with the most. `0`
+    /// turns the bars off. Defaults to 20 characters.
+    pub fn set_bar_width(&mut self, width: usize) {
+        self.bar_width = width;
+    }
+
+    /// Chooses what the percentages in reports are relative to. Defaults to
+    /// [`PercentageBase::WallClock`].
+    pub fn set_percentage_base(&mut self, base: PercentageBase) {
+        self.percentage_base = base;
+    }
+
+    // The seconds report percentages are relative to.
+    fn percentage_denominator(&self, total_duration: f64, freq: f64) -> f64 {
+        match self.percentage_base {
+            PercentageBase::WallClock => total_duration,
+            PercentageBase::InstrumentedTime => self.instrumented_ticks() as f64 / freq,
+        }
+    }
+
+    // ` ████░░░░` with the filled part proportional to the anchor's self time.
+    fn exclusive_bar(&self, anchor: &Anchor) -> String {
+        if self.bar_width == 0 {
+            return String::new();
+        }
+        let max_exclusive = self.anchors[1..].iter().map(|a| a.elapsed_exclusive).max().unwrap_or(0);
+        let filled = if max_exclusive == 0 {
+            0
+        } else {
+            (anchor.elapsed_exclusive as f64 / max_exclusive as f64 * self.bar_width as f64).round() as usize
+        };
+        let mut bar = String::from(" ");
+        bar.extend(std::iter::repeat_n('█', filled));
+        bar.extend(std::iter::repeat_n('░', self.bar_width - filled));
+        bar
+    }
+
+    // Whether the anchor gets a line in the report.
+    fn is_shown(&self, anchor: &Anchor, total_duration: f64, freq: f64) -> bool {
+        // Blocks too short to measure still show up if they processed data.
+        if anchor.elapsed_inclusive == 0 && anchor.bytes == 0 {
+            return false;
+        }
+        !self.hide_passthrough || anchor.elapsed_exclusive as f64 / freq >= total_duration * 0.001
+    }
+
+    /// How many blocks ended while a different block was the innermost open
+    /// one since the last reset. Anything but zero means exclusive times and
+    /// the call graph are unreliable for this run.
+    pub fn nesting_error_count(&self) -> usize {
+        self.nesting_error_count
+    }
+
+    /// Caps the number of anchors at `n`, so dynamically named blocks can't
+    /// grow the profiler without bound. Past the limit, looking up a new name
+    /// returns the root anchor's ID `0` and counts an overflow. Blocks of it
+    /// aren't recorded, so their time counts as the enclosing block's.
+    pub fn set_max_anchors(&mut self, n: usize) {
+        self.max_anchors = Some(n);
+    }
+
+    /// How many anchors were not created because of
+    /// [`Profiler::set_max_anchors`] since the last reset.
+    pub fn overflow_count(&self) -> usize {
+        self.overflow_count
+    }
+
+    /// Calls `cb` whenever a single call of the anchor named `name` spends
+    /// more than `max_self_ms` milliseconds in itself, not counting its
+    /// children. `cb` runs on the thread that ended the block, right after
+    /// the profiler is unlocked, so it may use the profiler itself.
+    pub fn on_threshold_exceeded(&mut self, name: &str, max_self_ms: f64, cb: impl Fn() + Send + 'static) {
+        self.threshold_callbacks.push((name.to_string(), max_self_ms, Arc::new(Mutex::new(cb))));
+    }
+
+    /// Calls `cb` with the anchor's name, the call's inclusive time in
+    /// nanoseconds and the block's payload (see `Block::with_payload`, `0`
+    /// without the `block-payload` feature) every time a block ends,
+    /// replacing any previous callback. Runs like the callbacks of
+    /// [`Profiler::on_threshold_exceeded`].
+    pub fn on_anchor_update(&mut self, cb: impl Fn(&str, u64, u64) + Send + 'static) {
+        self.anchor_update_callback = Some(Arc::new(Mutex::new(cb)));
+    }
+
+    /// Shows where each anchor's first block was started in printed
+    /// reports, e.g. `parse_json[3] [src/parser.rs:42]`. Off by default.
+    #[cfg(feature = "source-locations")]
+    pub fn set_include_source_locations(&mut self, enabled: bool) {
+        self.include_source_locations = enabled;
+    }
+
+    /// Leaves the first `n` calls of every anchor created from now on out of
+    /// the statistics, so cold caches and one-time setup don't skew them.
+    /// Their time stays with the enclosing block.
+    pub fn set_warmup_calls(&mut self, n: usize) {
+        self.warmup_calls = n;
+    }
+
+    /// Starts every line of printed reports with `prefix`, e.g. `"[PPROF] "`,
+    /// so they are easy to pick out of mixed logs. An empty prefix turns
+    /// this off.
+    pub fn set_log_prefix(&mut self, prefix: &str) {
+        self.log_prefix = Some(prefix.to_string()).filter(|p| !p.is_empty());
+    }
+
+    /// Replaces the `--- PProf Results ... ---` line at the top of reports
+    /// with `header`, e.g. a marker for a structured log format.
+    pub fn set_header(&mut self, header: &str) {
+        self.header = Some(header.to_string());
+    }
+
+    /// Ends reports with a `footer` line, e.g. a closing marker to go with
+    /// [`Profiler::set_header`]. Reports have no footer by default.
+    pub fn set_footer(&mut self, footer: &str) {
+        self.footer = Some(footer.to_string());
+    }
+
+    /// Sends printed reports to `target` instead of stdout. Reports the
+    /// program didn't ask for, like auto-printing, always go to stderr.
+    pub fn set_output_target(&mut self, target: OutputTarget) {
+        self.output_target = target;
+    }
+
+    fn write_output(&self, report: &str) {
+        let report = self.with_log_prefix(report);
+        match self.output_target {
+            OutputTarget::Stdout => write_locked(io::stdout().lock(), &report),
+            OutputTarget::Stderr => write_locked(io::stderr().lock(), &report),
+            // A single record, so the report isn't split across log entries.
+            #[cfg(feature = "log")]
+            OutputTarget::Log(level) => log::log!(level, "{}", report.trim_end()),
+        }
+    }
+
+    fn with_log_prefix<'a>(&self, report: &'a str) -> Cow<'a, str> {
+        match &self.log_prefix {
+            Some(prefix) => Cow::Owned(report.lines().map(|line| format!("{}{}\n", prefix, line)).collect()),
+            None => Cow::Borrowed(report),
+        }
+    }
+
+    /// Makes every report also show how much the total and self times of
+    /// each anchor grew since the previous [`Profiler::print`], which suits
+    /// periodic reporting from long-running programs.
+    pub fn enable_delta_mode(&mut self) {
+        self.delta_baseline.get_or_insert_with(Vec::new);
+    }
+
+    /// Attaches a note to the anchor named `name`, e.g. `"disk I/O, expected"`,
+    /// which reports print on the line below it. The anchor is registered if
+    /// it doesn't exist yet.
+    pub fn set_anchor_comment(&mut self, name: &str, comment: &str) {
+        let id = self.get_anchor_id(name);
+        self.anchors[id].comment = Some(comment.to_string());
+    }
+
+    /// Records a domain-specific statistic for the anchor named `name`, e.g.
+    /// a cache hit rate, replacing the previous value under the same `key`.
+    /// Reports list these after the anchor's times, and the JSON and CSV
+    /// exports include them. The anchor is registered if it doesn't exist yet.
+    pub fn add_custom_metric(&mut self, anchor_name: &str, key: &str, value: f64) {
+        let id = self.get_anchor_id(anchor_name);
+        self.add_custom_metric_by_id(id, key, value);
+    }
+
+    /// Like [`Profiler::add_custom_metric`], for an anchor ID.
+    pub fn add_custom_metric_by_id(&mut self, anchor_id: usize, key: &str, value: f64) {
+        self.anchors[anchor_id].custom_metrics.insert(key.to_string(), value);
+    }
+
+    /// Shows the anchor named `original` as `alias` in printed reports.
+    /// Lookups and exported data keep using the original name.
+    pub fn set_anchor_alias(&mut self, original: &str, alias: &str) {
+        self.aliases.insert(original.to_string(), alias.to_string());
+    }
+
+    /// How often blocks had to wait for another thread to release the
+    /// global profiler, since the program started.
+    pub fn lock_contention_count() -> u64 {
+        LOCK_CONTENTION.load(Ordering::Relaxed)
+    }
+
+    /// Roughly what one profiled block costs, in nanoseconds: the average of
+    /// 1000 empty blocks timed on a private profiler, locking included. It is
+    /// measured once and then reused.
+    pub fn estimate_overhead_ns() -> u64 {
+        static OVERHEAD_NS: std::sync::OnceLock<u64> = std::sync::OnceLock::new();
+        const RUNS: u32 = 1000;
+        *OVERHEAD_NS.get_or_init(|| {
+            let profiler = Mutex::new(Profiler::new());
+            let id = profiler.lock().unwrap().get_static_anchor_id("overhead");
+            let start = Instant::now();
+            for _ in 0..RUNS {
+                let mut block = profiler.lock().unwrap().begin_block(id);
+                // Ended by hand, since dropping it would record it in the
+                // global profiler.
+                let ended = block.ended();
+                block.recorded = false;
+                profiler.lock().unwrap().end_block(ended);
+            }
+            (start.elapsed() / RUNS).as_nanos() as u64
+        })
+    }
+
+    pub fn is_wall_clock_mode(&self) -> bool {
+        cfg!(not(feature = "rdtsc")) || self.wall_clock_mode
+    }
+
+    // Ticks per second of the clock blocks are currently timed with.
+    fn freq(&self) -> f64 {
+        if self.wall_clock_mode {
+            1_000_000_000.0
+        } else {
+            get_duration_freq()
+        }
+    }
+
+    fn display_name<'a>(&'a self, anchor: &'a Anchor) -> Cow<'a, str> {
+        let name: &'a str = self.aliases.get(anchor.name.as_ref()).map_or(&anchor.name, |alias| alias);
+        let name = sanitize_name(name);
+        if name.chars().count() <= self.max_name_length {
+            return name;
+        }
+        let mut truncated: String = name.chars().take(self.max_name_length.saturating_sub(1)).collect();
+        truncated.push('…');
+        Cow::Owned(truncated)
+    }
+
+    #[inline]
+    #[cfg_attr(feature = "source-locations", track_caller)]
+    fn begin_block(&mut self, id: usize) -> Block {
+        self.begin_block_with_parent(id, self.parent_id)
+    }
+
+    // Starts a block attributed to `parent_id`, which is the innermost open
+    // block unless the caller knows better.
+    #[inline]
+    #[cfg_attr(feature = "source-locations", track_caller)]
+    fn begin_block_with_parent(&mut self, id: usize, parent_id: usize) -> Block {
+        // Anchors past `max_anchors` are looked up as the root. Recording a
+        // block of it would move its time from the enclosing block to the
+        // root, so it is skipped and its time stays with the enclosing block.
+        if !self.enabled || id == 0 {
+            return Block::skipped();
+        }
+        #[cfg(feature = "source-locations")]
+        {
+            // The `block!` or `time!` invocation, as every function between
+            // it and here tracks its caller.
+            let location = std::panic::Location::caller();
+            let anchor = &mut self.anchors[id];
+            if anchor.source_file.is_empty() {
+                anchor.source_file = location.file();
+                anchor.source_line = location.line();
+            } else if (anchor.source_file, anchor.source_line) != (location.file(), location.line()) {
+                anchor.other_source_locations.insert((location.file(), location.line()));
+            }
+        }
+        let outer_id = self.parent_id;
+        let anchor = &mut self.anchors[id];
+        let old_elapsed_inclusive = anchor.elapsed_inclusive;
+        let old_elapsed_exclusive = anchor.elapsed_exclusive;
+        // A block opened while another one of the same anchor is still open
+        // means the anchor is somewhere in its own parent chain.
+        anchor.is_recursive |= anchor.open > 0;
+        anchor.open += 1;
+        self.parent_id = id;
+        self.current_depth += 1;
+        self.max_depth = self.max_depth.max(self.current_depth);
+        let mut block = Block::with_clock(id, parent_id, old_elapsed_inclusive, self.wall_clock_mode);
+        block.old_elapsed_exclusive = old_elapsed_exclusive;
+        #[cfg(feature = "counting-allocator")]
+        {
+            block.old_allocations = (self.anchors[id].alloc_count, self.anchors[id].alloc_bytes);
+        }
+        block.outer_id = outer_id;
+        block
+    }
+
+    fn end_block(&mut self, block: EndedBlock) {
+        let elapsed = block.elapsed;
+        // Blocks must end in the reverse order they started in. If another
+        // block is innermost, drops were interleaved (async code, several
+        // threads) and the parent-child accounting can't be trusted.
+        if self.parent_id != block.anchor_id {
+            self.nesting_error_count += 1;
+        }
+        self.parent_id = block.outer_id;
+        self.current_depth -= 1;
+        self.anchors[block.anchor_id].open -= 1;
+        if self.anchors[block.anchor_id].warmup_remaining > 0 {
+            self.anchors[block.anchor_id].warmup_remaining -= 1;
+            self.skip_warmup_call(&block);
+            #[cfg(feature = "atomic-stats")]
+            self.publish_atomic_stats(&[block.parent_id]);
+            return;
+        }
+        self.anchors[block.parent_id].elapsed_exclusive =
+            self.anchors[block.parent_id].elapsed_exclusive.wrapping_sub(elapsed);
+        self.anchors[block.anchor_id].elapsed_exclusive =
+            self.anchors[block.anchor_id].elapsed_exclusive.wrapping_add(elapsed);
+        self.anchors[block.anchor_id].elapsed_inclusive = block.old_elapsed_inclusive + elapsed;
+        self.anchors[block.anchor_id].calls += 1;
+        #[cfg(feature = "atomic-stats")]
+        self.publish_atomic_stats(&[block.anchor_id, block.parent_id]);
+        if !self.threshold_callbacks.is_empty() {
+            self.check_thresholds(&block);
+        }
+        if let Some(cb) = &self.anchor_update_callback {
+            let cb = Arc::clone(cb);
+            let name = self.anchors[block.anchor_id].name.clone();
+            let elapsed_ns = (elapsed as f64 / self.freq() * 1_000_000_000.0) as u64;
+            #[cfg(feature = "block-payload")]
+            let payload = block.payload;
+            #[cfg(not(feature = "block-payload"))]
+            let payload = 0;
+            FIRED.with(|fired| {
+                fired.borrow_mut().push(Box::new(move || {
+                    if let Ok(cb) = cb.try_lock() {
+                        cb(&name, elapsed_ns, payload);
+                    }
+                }))
+            });
+        }
+        #[cfg(feature = "counting-allocator")]
+        {
+            // Same exclusive accounting as for time: the parent gives up
+            // what its child allocated.
+            let (count, bytes) = block.allocations;
+            let parent = &mut self.anchors[block.parent_id];
+            parent.alloc_count = parent.alloc_count.wrapping_sub(count);
+            parent.alloc_bytes = parent.alloc_bytes.wrapping_sub(bytes);
+            let anchor = &mut self.anchors[block.anchor_id];
+            anchor.alloc_count = anchor.alloc_count.wrapping_add(count);
+            anchor.alloc_bytes = anchor.alloc_bytes.wrapping_add(bytes);
+        }
+        #[cfg(feature = "percentiles")]
+        self.anchors[block.anchor_id].durations.push(elapsed);
+        #[cfg(feature = "timeline")]
+        {
+            let end_ns = block.ended_at.saturating_duration_since(self.start).as_nanos() as u64;
+            let elapsed_ns = (elapsed as f64 / self.freq() * 1_000_000_000.0) as u64;
+            let anchor = &mut self.anchors[block.anchor_id];
+            if anchor.calls == 1 {
+                anchor.first_start_ns = end_ns.saturating_sub(elapsed_ns);
+            }
+            anchor.last_end_ns = end_ns;
+        }
+        let edge = self.edges.entry((block.parent_id, block.anchor_id)).or_default();
+        edge.calls += 1;
+        edge.elapsed_inclusive += elapsed;
+
+        self.drop_count += 1;
+        if self.auto_print_interval != 0 && self.drop_count.is_multiple_of(self.auto_print_interval) {
+            self.eprint_report();
+        }
+    }
+
+    // Leaves a warmup call out of its anchor's statistics. Its children have
+    // already taken their time out of its self time; that goes to the
+    // enclosing block instead, which keeps the call's own time.
+    fn skip_warmup_call(&mut self, block: &EndedBlock) {
+        let anchor = &mut self.anchors[block.anchor_id];
+        let children = block.old_elapsed_exclusive.wrapping_sub(anchor.elapsed_exclusive);
+        anchor.elapsed_exclusive = block.old_elapsed_exclusive;
+        let parent = &mut self.anchors[block.parent_id];
+        parent.elapsed_exclusive = parent.elapsed_exclusive.wrapping_sub(children);
+        #[cfg(feature = "counting-allocator")]
+        {
+            let anchor = &mut self.anchors[block.anchor_id];
+            let (old_count, old_bytes) = block.old_allocations;
+            let children = (old_count.wrapping_sub(anchor.alloc_count), old_bytes.wrapping_sub(anchor.alloc_bytes));
+            (anchor.alloc_count, anchor.alloc_bytes) = block.old_allocations;
+            let parent = &mut self.anchors[block.parent_id];
+            parent.alloc_count = parent.alloc_count.wrapping_sub(children.0);
+            parent.alloc_bytes = parent.alloc_bytes.wrapping_sub(children.1);
+        }
+    }
+
+    // Queues the callbacks whose threshold the call in `block` exceeded.
+    fn check_thresholds(&self, block: &EndedBlock) {
+        let anchor = &self.anchors[block.anchor_id];
+        // The children of this call took their time out of the exclusive
+        // time since it started.
+        let self_ticks = anchor.elapsed_exclusive.wrapping_sub(block.old_elapsed_exclusive);
+        let self_ms = self_ticks as f64 / self.freq() * 1000.0;
+        for (name, max_self_ms, cb) in &self.threshold_callbacks {
+            if self_ms > *max_self_ms && *name == anchor.name {
+                let cb = Arc::clone(cb);
+                FIRED.with(|fired| {
+                    fired.borrow_mut().push(Box::new(move || {
+                        if let Ok(cb) = cb.try_lock() {
+                            cb();
+                        }
+                    }))
+                });
+            }
+        }
+    }
+
+    // Like `print()`, but to stderr, for reports the program didn't ask for.
+    fn eprint_report(&self) {
+        let mut report = String::new();
+        let _ = self.write_report(&mut report);
+        let report = self.uncolored_unless(&report, io::stderr().is_terminal());
+        write_locked(io::stderr().lock(), &self.with_log_prefix(&report));
+    }
+
+    /// Prints the report to the output target. While blocks are open the
+    /// header says how many, and debug builds also warn on stderr, see
+    /// [`Profiler::assert_no_leaked_blocks`].
+    pub fn print(&mut self) {
+        self.print_report(None);
+    }
+
+    /// Like [`Profiler::print`], with `context` (e.g. a build hash or request
+    /// ID) on the line after the header, see
+    /// [`Profiler::report_text_with_context`].
+    pub fn print_with_context(&mut self, context: &str) {
+        self.print_report(Some(context));
+    }
+
+    fn print_report(&mut self, context: Option<&str>) {
+        // Only a warning: the open block may belong to another thread, and
+        // printing from inside a block, e.g. periodically from a
+        // long-running loop, is legitimate.
+        if cfg!(debug_assertions) {
+            if let Err(e) = self.assert_no_leaked_blocks() {
+                eprintln!("pprof warning: printing an incomplete profile, {}", e);
+            }
+        }
+        let mut report = String::new();
+        let _ = self.write_report_impl(&mut report, context);
+        self.write_output(&report);
+        self.last_print_time = Some(Instant::now());
+        if self.delta_baseline.is_some() {
+            self.delta_baseline = Some(self.results().anchors);
+        }
+    }
+
+    /// Prints the report only if some anchor's self time exceeds
+    /// `threshold_ms`, and returns whether it did. Handy after handling a
+    /// request, so that only slow requests dump their profile.
+    pub fn print_if_slow(&mut self, threshold_ms: f64) -> bool {
+        let freq = self.freq();
+        let slow = self.anchors[1..]
+            .iter()
+            .any(|anchor| anchor.elapsed_exclusive as f64 / freq * 1000.0 > threshold_ms);
+        if slow {
+            self.print();
+        }
+        slow
+    }
+
+    pub fn write_report<W: fmt::Write>(&self, out: &mut W) -> fmt::Result {
+        self.write_report_impl(out, None)
+    }
+
+    /// Writes the report like [`Profiler::write_report`], with a
+    /// `Context: <context>` line after the header. The context is free-form,
+    /// e.g. `"build=abc1234 host=prod-1 req=42"`.
+    pub fn report_text_with_context<W: fmt::Write>(&self, out: &mut W, context: &str) -> fmt::Result {
+        self.write_report_impl(out, Some(context))
+    }
+
+    fn write_report_impl<W: fmt::Write>(&self, out: &mut W, context: Option<&str>) -> fmt::Result {
+        let total_duration = self.elapsed().as_nanos() as f64 / 1_000_000_000.0;
+        let freq = self.freq();
+        self.write_header(out, total_duration, freq, context)?;
+        let ranks = self.hotness_ranks();
+        let slow_threshold = self.slow_threshold();
+        for (anchor, &rank) in self.anchors.iter().zip(&ranks) {
+            // The root sentinel only exists so that top-level blocks have a
+            // parent to debit; it is never a real anchor.
+            if anchor.name.is_empty() {
+                continue;
+            }
+            if self.is_shown(anchor, total_duration, freq) {
+                self.write_anchor_line(out, anchor, rank, slow_threshold, total_duration, freq)?;
+            }
+        }
+        self.write_totals(out, total_duration, freq)
+    }
+
+    /// Prints the report with anchors in the order given by `key` rather
+    /// than in registration order.
+    pub fn print_sorted_by(&mut self, key: SortKey) {
+        self.print_sorted_by_multi(&[key]);
+    }
+
+    /// Like [`Profiler::print_sorted_by`], sorting by each key in turn for
+    /// the anchors that tie on all the ones before it, e.g.
+    /// `&[SortKey::CallsDesc, SortKey::SelfTimeDesc]`.
+    pub fn print_sorted_by_multi(&mut self, keys: &[SortKey]) {
+        let total_duration = self.elapsed().as_nanos() as f64 / 1_000_000_000.0;
+        let freq = self.freq();
+
+        let ranks = self.hotness_ranks();
+        let slow_threshold = self.slow_threshold();
+        let mut ids: Vec<usize> = (1..self.anchors.len())
+            .filter(|&id| self.is_shown(&self.anchors[id], total_duration, freq))
+            .collect();
+        ids.sort_by(|&a, &b| {
+            let (a, b) = (&self.anchors[a], &self.anchors[b]);
+            keys.iter()
+                .chain([&SortKey::NameAsc])
+                .map(|key| key.compare(a, b))
+                .find(|order| order.is_ne())
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+
+        let mut report = String::new();
+        let _ = self.write_header(&mut report, total_duration, freq, None);
+        for id in ids {
+            let _ = self.write_anchor_line(&mut report, &self.anchors[id], ranks[id], slow_threshold, total_duration, freq);
+        }
+        let _ = self.write_totals(&mut report, total_duration, freq);
+        self.write_output(&report);
+    }
+
+    /// Prints the report with anchor names shown without their module path,
+    /// e.g. `parse` for `my_crate::parser::json::parse`. Anchors are still
+    /// looked up by their full names. When several anchors end up with the
+    /// same short name, the second one shows as `parse (2)` and so on.
+    pub fn print_function_only(&mut self) {
+        let mut seen: HashMap<&str, usize> = HashMap::new();
+        let mut short_names = HashMap::new();
+        for anchor in &self.anchors[1..] {
+            let name = self.aliases.get(anchor.name.as_ref()).map_or(anchor.name.as_ref(), String::as_str);
+            let short = function_name(name);
+            let count = seen.entry(short).or_insert(0);
+            *count += 1;
+            let short = if *count == 1 { short.to_string() } else { format!("{} ({})", short, count) };
+            short_names.insert(anchor.name.to_string(), short);
+        }
+        // Shown through the alias mechanism for this one report.
+        let aliases = std::mem::replace(&mut self.aliases, short_names);
+        self.print();
+        self.aliases = aliases;
+    }
+
+    /// Prints the report level by level: the top-level anchors under
+    /// `--- Depth 1 ---`, then the anchors they call under `--- Depth 2 ---`,
+    /// and so on, each group sorted by self time. An anchor called at several
+    /// depths shows up once, at the deepest one.
+    pub fn print_by_depth(&mut self) {
+        let total_duration = self.elapsed().as_nanos() as f64 / 1_000_000_000.0;
+        let freq = self.freq();
+
+        let mut depths = vec![1; self.anchors.len()];
+        self.visit_call_paths(|path, _, _| {
+            let leaf = path[path.len() - 1];
+            depths[leaf] = depths[leaf].max(path.len());
+        });
+        let ranks = self.hotness_ranks();
+        let slow_threshold = self.slow_threshold();
+        let mut groups: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for (id, &depth) in depths.iter().enumerate().skip(1) {
+            if self.is_shown(&self.anchors[id], total_duration, freq) {
+                groups.entry(depth).or_default().push(id);
+            }
+        }
+
+        let mut report = String::new();
+        let _ = self.write_header(&mut report, total_duration, freq, None);
+        for (depth, ids) in &mut groups {
+            ids.sort_by(|&a, &b| {
+                let (a, b) = (&self.anchors[a], &self.anchors[b]);
+                SortKey::SelfTimeDesc.compare(a, b).then_with(|| SortKey::NameAsc.compare(a, b))
+            });
+            let _ = writeln!(report, "--- Depth {} ---", depth);
+            for &id in ids.iter() {
+                let _ = self.write_anchor_line(&mut report, &self.anchors[id], ranks[id], slow_threshold, total_duration, freq);
+            }
+        }
+        let _ = self.write_totals(&mut report, total_duration, freq);
+        self.write_output(&report);
+    }
+
+    /// Prints the report with anchors grouped under their module path (the
+    /// part of the name before the last `::`), with a subtotal per module.
+    pub fn print_grouped_by_module(&mut self) {
+        self.print_grouped(
+            |anchor| module_path(&anchor.name).to_string(),
+            |module| format!("{}:", if module.is_empty() { "(no module)" } else { module }),
+        );
+    }
+
+    /// Puts the anchor named `anchor_name` in `group` for
+    /// [`Profiler::print_by_group`]. The anchor is registered if it doesn't
+    /// exist yet.
+    pub fn set_anchor_group(&mut self, anchor_name: &str, group: &str) {
+        let id = self.get_anchor_id(anchor_name);
+        self.anchors[id].group = Some(group.to_string());
+    }
+
+    /// Prints the report with anchors under `=== <group> ===` headings, as
+    /// set with [`Profiler::set_anchor_group`], with a subtotal per group.
+    /// Anchors without a group come last, under `=== Other ===`.
+    pub fn print_by_group(&mut self) {
+        self.print_grouped(
+            // Sorts ungrouped anchors after all groups.
+            |anchor| (anchor.group.is_none(), anchor.group.clone().unwrap_or_default()),
+            |(ungrouped, group)| format!("=== {} ===", if *ungrouped { "Other" } else { group }),
+        );
+    }
+
+    // Prints the shown anchors bucketed by `group_of`, in key order, each
+    // bucket under its `heading` and followed by a subtotal.
+    fn print_grouped<K: Ord>(&self, group_of: impl Fn(&Anchor) -> K, heading: impl Fn(&K) -> String) {
+        let total_duration = self.elapsed().as_nanos() as f64 / 1_000_000_000.0;
+        let freq = self.freq();
+
+        let ranks = self.hotness_ranks();
+        let slow_threshold = self.slow_threshold();
+        let mut groups: BTreeMap<K, Vec<(&Anchor, Option<usize>)>> = BTreeMap::new();
+        for (anchor, &rank) in self.anchors.iter().zip(&ranks) {
+            if anchor.name.is_empty() {
+                continue;
+            }
+            if self.is_shown(anchor, total_duration, freq) {
+                groups.entry(group_of(anchor)).or_default().push((anchor, rank));
+            }
+        }
+
+        let mut report = String::new();
+        let _ = self.write_header(&mut report, total_duration, freq, None);
+        for (group, anchors) in &groups {
+            let _ = writeln!(report, "{}", heading(group));
+            for &(anchor, rank) in anchors {
+                report.push_str("  ");
+                let _ = self.write_anchor_line(&mut report, anchor, rank, slow_threshold, total_duration, freq);
+            }
+            let self_elapsed =
+                anchors.iter().fold(0u64, |sum, (a, _)| sum.wrapping_add(a.elapsed_exclusive)) as f64 / freq;
+            let _ = writeln!(
+                report,
+                "  subtotal - self={:.prec$}ms ({})",
+                self_elapsed * 1000.0,
+                percentage(self_elapsed, self.percentage_denominator(total_duration, freq), self.precision),
+                prec = self.precision,
+            );
+        }
+        let _ = self.write_totals(&mut report, total_duration, freq);
+        self.write_output(&report);
+    }
+
+    /// Prints the average time per call of every anchor instead of the
+    /// summed times, which makes anchors with very different call counts
+    /// easier to compare.
+    pub fn print_normalized(&mut self) {
+        let total_duration = self.elapsed().as_nanos() as f64 / 1_000_000_000.0;
+        let freq = self.freq();
+
+        let mut report = String::new();
+        let _ = self.write_header(&mut report, total_duration, freq, None);
+        for anchor in &self.anchors {
+            if anchor.name.is_empty() || anchor.calls == 0 {
+                continue;
+            }
+            let calls = anchor.calls as f64;
+            let _ = writeln!(
+                report,
+                "{}[{}] - avg_self={:.prec$}ms avg_total={:.prec$}ms",
+                self.display_name(anchor),
+                anchor.calls,
+                anchor.elapsed_exclusive as f64 / freq / calls * 1000.0,
+                anchor.elapsed_inclusive as f64 / freq / calls * 1000.0,
+                prec = self.precision,
+            );
+        }
+        let _ = self.write_totals(&mut report, total_duration, freq);
+        self.write_output(&report);
+    }
+
+    /// Prints the `n` anchors with the most self time, each followed by its
+    /// three biggest callers and their share of the time spent in it.
+    pub fn print_top_n_with_callers(&mut self, n: usize) {
+        let total_duration = self.elapsed().as_nanos() as f64 / 1_000_000_000.0;
+        let freq = self.freq();
+
+        let mut ids: Vec<usize> = (1..self.anchors.len()).filter(|&id| self.anchors[id].calls != 0).collect();
+        ids.sort_by_key(|&id| std::cmp::Reverse(self.anchors[id].elapsed_exclusive));
+        ids.truncate(n);
+
+        let mut report = String::new();
+        let _ = self.write_header(&mut report, total_duration, freq, None);
+        for id in ids {
+            let anchor = &self.anchors[id];
+            let self_elapsed = anchor.elapsed_exclusive as f64 / freq;
+            let _ = write!(
+                report,
+                "{} [{}] self={:.prec$}ms ({})",
+                self.display_name(anchor),
+                anchor.calls,
+                self_elapsed * 1000.0,
+                percentage(self_elapsed, self.percentage_denominator(total_duration, freq), self.precision),
+                prec = self.precision,
+            );
+
+            let mut callers: Vec<(usize, u64)> = self
+                .edges
+                .iter()
+                .filter(|(&(parent, child), _)| child == id && parent != id)
+                .map(|(&(parent, _), edge)| (parent, edge.elapsed_inclusive))
+                .collect();
+            let called_total: u64 = callers.iter().map(|&(_, elapsed)| elapsed).sum();
+            if called_total != 0 {
+                callers.sort_by_key(|&(_, elapsed)| std::cmp::Reverse(elapsed));
+                let share = |elapsed: u64| elapsed as f64 / called_total as f64 * 100.0;
+                let mut parts: Vec<String> = callers
+                    .iter()
+                    .take(3)
+                    .map(|&(parent, elapsed)| {
+                        let name = if parent == 0 { Cow::Borrowed("(top level)") } else { self.display_name(&self.anchors[parent]) };
+                        format!("{} ({:.prec$}%)", name, share(elapsed), prec = self.precision)
+                    })
+                    .collect();
+                let other: u64 = callers.iter().skip(3).map(|&(_, elapsed)| elapsed).sum();
+                if other != 0 {
+                    parts.push(format!("other ({:.prec$}%)", share(other), prec = self.precision));
+                }
+                let _ = write!(report, " <- called from: {}", parts.join(", "));
+            }
+            report.push('\n');
+        }
+        let _ = self.write_totals(&mut report, total_duration, freq);
+        self.write_output(&report);
+    }
+
+    /// Prints the call tree, one line per call path indented by depth, with
+    /// the calls and times attributed to that path. A recursive anchor
+    /// shows up once, as `name × N` with `N` the average recursion depth
+    /// per outside call, instead of once per level.
+    pub fn print_tree(&mut self) {
+        let total_duration = self.elapsed().as_nanos() as f64 / 1_000_000_000.0;
+        let freq = self.freq();
+
+        let mut report = String::new();
+        let _ = self.write_header(&mut report, total_duration, freq, None);
+        self.visit_call_paths(|path, fraction, inclusive| {
+            let id = *path.last().unwrap();
+            let anchor = &self.anchors[id];
+            let recursion = match self.edges.get(&(id, id)) {
+                Some(edge) if edge.calls < anchor.calls => {
+                    format!(" × {:.0}", anchor.calls as f64 / (anchor.calls - edge.calls) as f64)
+                }
+                _ => String::new(),
+            };
+            let _ = writeln!(
+                report,
+                "{:indent$}{}[{}]{} - total={:.prec$}ms self={:.prec$}ms",
+                "",
+                self.display_name(anchor),
+                (anchor.calls as f64 * fraction).round(),
+                recursion,
+                inclusive / freq * 1000.0,
+                anchor.elapsed_exclusive as f64 * fraction / freq * 1000.0,
+                indent = (path.len() - 1) * 2,
+                prec = self.precision,
+            );
+        });
+        let _ = self.write_totals(&mut report, total_duration, freq);
+        self.write_output(&report);
+    }
+
+    /// Visits the call tree depth first, the same paths as
+    /// [`Profiler::print_tree`], calling `visitor` with the depth (0 for
+    /// top-level anchors) and the anchor's statistics on that path: calls and
+    /// times are the share attributed to the path, not the anchor's totals.
+    /// A recursive anchor is visited once per path, not once per level.
+    pub fn walk_call_tree<F: FnMut(usize, &AnchorResult)>(&self, mut visitor: F) {
+        let freq = self.freq();
+        self.visit_call_paths(|path, fraction, inclusive| {
+            let mut result = self.anchors[*path.last().unwrap()].result(freq);
+            result.calls = (result.calls as f64 * fraction).round() as usize;
+            result.bytes = (result.bytes as f64 * fraction).round() as usize;
+            result.ticks_exclusive = (result.ticks_exclusive as f64 * fraction).round() as u64;
+            result.ticks_inclusive = inclusive.round() as u64;
+            result.exclusive_ms = result.ticks_exclusive as f64 / freq * 1000.0;
+            result.inclusive_ms = inclusive / freq * 1000.0;
+            visitor(path.len() - 1, &result);
+        });
+    }
+
+    fn write_header<W: fmt::Write>(
+        &self,
+        out: &mut W,
+        total_duration: f64,
+        freq: f64,
+        context: Option<&str>,
+    ) -> fmt::Result {
+        if let Some(header) = &self.header {
+            writeln!(out, "{}", header)?;
+        } else {
+            // `rust-version` from the manifest, empty unless it sets one.
+            let rust_version = env!("CARGO_PKG_RUST_VERSION");
+            writeln!(
+                out,
+                "--- PProf Results [pprof v{}{}] [id={}] ---",
+                env!("CARGO_PKG_VERSION"),
+                if rust_version.is_empty() { String::new() } else { format!(", rust {}", rust_version) },
+                self.generate_report_id(),
+            )?;
+        }
+        if let Some(context) = context {
+            writeln!(out, "Context: {}", context)?;
+        }
+        if self.is_wall_clock_mode() {
+            writeln!(out, "Timer: Instant (monotonic)")?;
+        } else {
+            writeln!(out, "Timer: rdtsc (calibrated at {:.1} GHz)", freq / 1e9)?;
+        }
+        if total_duration > 60.0 {
+            writeln!(
+                out,
+                "Total time: {} ({:.prec$}ms)",
+                format_duration_human(total_duration * 1000.0),
+                total_duration * 1000.0,
+                prec = self.precision,
+            )?;
+        } else {
+            writeln!(out, "Total time: {:.prec$}ms", total_duration * 1000.0, prec = self.precision)?;
+        }
+        if let Some(interval) = self.elapsed_since_last_print() {
+            writeln!(out, "Interval: {:.3}s", interval.as_secs_f64())?;
+        }
+        let instrumented = self.instrumented_ticks() as f64 / freq;
+        writeln!(
+            out,
+            "Instrumented: {} ({:.prec$}ms / {:.prec$}ms)",
+            percentage(instrumented, total_duration, 1),
+            instrumented * 1000.0,
+            total_duration * 1000.0,
+            prec = self.precision,
+        )?;
+        writeln!(out, "Max depth: {}", self.max_depth)?;
+        // Open blocks only add their time to the statistics when they end, so
+        // whatever ran inside them so far is missing or misattributed.
+        if self.current_depth != 0 {
+            writeln!(out, "Active blocks at print time: {} (statistics are partial)", self.current_depth)?;
+        }
+        Ok(())
+    }
+
+    // Each anchor's place when sorted by self time, hottest first, indexed by
+    // anchor ID. Ties keep registration order, so no two anchors share a
+    // rank; anchors without self time have none.
+    fn hotness_ranks(&self) -> Vec<Option<usize>> {
+        let mut ids: Vec<usize> = (1..self.anchors.len()).filter(|&id| self.anchors[id].elapsed_exclusive != 0).collect();
+        ids.sort_by_key(|&id| std::cmp::Reverse(self.anchors[id].elapsed_exclusive));
+        let mut ranks = vec![None; self.anchors.len()];
+        for (rank, id) in ids.into_iter().enumerate() {
+            ranks[id] = Some(rank + 1);
+        }
+        ranks
+    }
+
+    fn write_anchor_line<W: fmt::Write>(
+        &self,
+        out: &mut W,
+        anchor: &Anchor,
+        rank: Option<usize>,
+        slow_threshold: Option<u64>,
+        total_duration: f64,
+        freq: f64,
+    ) -> fmt::Result {
+        let elapsed = anchor.elapsed_inclusive as f64 / freq;
+        let self_elapsed = anchor.elapsed_exclusive as f64 / freq;
+        let base = self.percentage_denominator(total_duration, freq);
+        let self_elapsed_percentage = self_elapsed / base * 100.0;
+
+        let throughput_str = if anchor.bytes != 0 && anchor.elapsed_inclusive == 0 {
+            // Bytes added to a block too short for the timer to measure.
+            format!(" throughput={} at >1 TB/s (timer resolution insufficient)", format_bytes(anchor.bytes as u64))
+        } else if anchor.bytes != 0 {
+            let gb = (1024 * 1024 * 1024) as f64;
+            // Self time per byte shows how efficiently the anchor's own code
+            // (a parser, a serializer) handles its data.
+            format!(
+                " throughput={} at {} ns/B={:.1}",
+                format_bytes(anchor.bytes as u64),
+                throughput(anchor.bytes as f64 / gb, elapsed, self.precision),
+                self_elapsed * 1e9 / anchor.bytes as f64,
+            )
+        } else {
+            String::new()
+        };
+
+        #[cfg(feature = "counting-allocator")]
+        let throughput_str = if anchor.alloc_count != 0 {
+            format!(
+                "{} allocs={} ({:.prec$}kb)",
+                throughput_str,
+                anchor.alloc_count,
+                anchor.alloc_bytes as f64 / 1024.0,
+                prec = self.precision,
+            )
+        } else {
+            throughput_str
+        };
+
+        // Changes since the last `print()` in delta mode.
+        let (total_delta, self_delta) = match &self.delta_baseline {
+            Some(baseline) => {
+                let (total_ms, self_ms) = baseline
+                    .iter()
+                    .find(|a| a.name == anchor.name)
+                    .map_or((0.0, 0.0), |a| (a.inclusive_ms, a.exclusive_ms));
+                (
+                    format!(" ({:+.prec$}ms)", elapsed * 1000.0 - total_ms, prec = self.precision),
+                    format!(" ({:+.prec$}ms)", self_elapsed * 1000.0 - self_ms, prec = self.precision),
+                )
+            }
+            None => (String::new(), String::new()),
+        };
+
+        let extra = if anchor.custom_metrics.is_empty() {
+            String::new()
+        } else {
+            let metrics: Vec<String> = anchor
+                .custom_metrics
+                .iter()
+                .map(|(key, value)| format!("{}={}", sanitize_name(key), value))
+                .collect();
+            format!(" extra: {}", metrics.join(" "))
+        };
+        let throughput_str = throughput_str + &extra;
+
+        // Share of the anchor's time spent in its own code rather than in
+        // callees: 1.0 for leaves, near 0.0 for functions that only delegate.
+        let leaf_ratio = if anchor.elapsed_inclusive != 0 {
+            format!(" leaf_ratio={:.2}", anchor.elapsed_exclusive as f64 / anchor.elapsed_inclusive as f64)
+        } else {
+            String::new()
+        };
+
+        // The most the whole run could speed up if this anchor's self time
+        // went to zero, by Amdahl's law.
+        let amdahl = if self_elapsed_percentage > 0.0 && self_elapsed_percentage < 100.0 {
+            format!(" Amdahl: {:.1}x", 1.0 / (1.0 - self_elapsed_percentage / 100.0))
+        } else {
+            String::new()
+        };
+
+        let rank = rank.map_or_else(|| "-".to_string(), |rank| format!("#{}", rank));
+        // When the anchor first ran, relative to the profiler's start.
+        #[cfg(feature = "timeline")]
+        let offset = format!(" @T+{:.1}ms", anchor.first_start_ns as f64 / 1_000_000.0);
+        #[cfg(not(feature = "timeline"))]
+        let offset = "";
+        #[cfg(feature = "source-locations")]
+        let location = if self.include_source_locations && !anchor.source_file.is_empty() {
+            format!(" [{}:{}]", anchor.source_file, anchor.source_line)
+        } else {
+            String::new()
+        };
+        #[cfg(not(feature = "source-locations"))]
+        let location = "";
+        // A saturated counter means a timer bug, e.g. a TSC going backwards,
+        // and anything computed from it would be misleading.
+        let overflowed = anchor.elapsed_inclusive == u64::MAX || anchor.elapsed_exclusive == u64::MAX;
+        let slow = slow_threshold.is_some_and(|threshold| anchor.elapsed_exclusive > threshold);
+        let line = if overflowed {
+            let ms_or_overflow = |ticks: u64| {
+                if ticks == u64::MAX {
+                    "OVERFLOW".to_string()
+                } else {
+                    format!("{:.prec$}ms", ticks as f64 / freq * 1000.0, prec = self.precision)
+                }
+            };
+            format!(
+                "{} {}[{}] - total={} self={}",
+                rank,
+                self.display_name(anchor),
+                anchor.calls,
+                ms_or_overflow(anchor.elapsed_inclusive),
+                ms_or_overflow(anchor.elapsed_exclusive),
+            )
+        } else {
+            format!(
+                "{} {}[{}]{}{}{}{} - total={:.prec$}ms{} ({}) self={:.prec$}ms{} ({}){}{}{}{}",
+                rank,
+                self.display_name(anchor),
+                anchor.calls,
+                location,
+                if anchor.is_recursive { " [REC]" } else { "" },
+                if slow { " [SLOW]" } else { "" },
+                offset,
+                elapsed * 1000.0,
+                total_delta,
+                percentage(elapsed, base, self.precision),
+                self_elapsed * 1000.0,
+                self_delta,
+                percentage(self_elapsed, base, self.precision),
+                leaf_ratio,
+                throughput_str,
+                amdahl,
+                self.exclusive_bar(anchor),
+                prec = self.precision,
+            )
+        };
+
+        let color = if !self.use_color() || overflowed {
+            None
+        } else if slow || self_elapsed_percentage > 50.0 {
+            Some(ANSI_RED)
+        } else if self_elapsed_percentage >= 20.0 {
+            Some(ANSI_YELLOW)
+        } else {
+            None
+        };
+        match color {
+            Some(color) => writeln!(out, "{}{}{}", color, line, ANSI_RESET)?,
+            None => writeln!(out, "{}", line)?,
+        }
+        if let Some(comment) = &anchor.comment {
+            writeln!(out, "  # {}", sanitize_name(comment))?;
+        }
+        #[cfg(feature = "percentiles")]
+        self.write_histogram(out, anchor, freq)?;
+        Ok(())
+    }
+
+    // Writes `    | ▂▄▆█▆▄▂▁▁▁ | p50=.. p95=.. p99=..` for the anchor's call
+    // durations, bucketed on a log scale between the fastest and slowest.
+    #[cfg(feature = "percentiles")]
+    fn write_histogram<W: fmt::Write>(&self, out: &mut W, anchor: &Anchor, freq: f64) -> fmt::Result {
+        const BUCKETS: usize = 10;
+        const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+        if anchor.durations.is_empty() {
+            return Ok(());
+        }
+        let mut sorted = anchor.durations.clone();
+        sorted.sort_unstable();
+        let min = (sorted[0].max(1) as f64).ln();
+        let max = (sorted[sorted.len() - 1].max(1) as f64).ln();
+
+        let mut counts = [0usize; BUCKETS];
+        for &d in &sorted {
+            let position = if max > min { ((d.max(1) as f64).ln() - min) / (max - min) } else { 0.0 };
+            counts[((position * BUCKETS as f64) as usize).min(BUCKETS - 1)] += 1;
+        }
+        let highest = counts.iter().copied().max().unwrap_or(1);
+        let bars: String = counts
+            .iter()
+            .map(|&c| if c == 0 { ' ' } else { BARS[(c * BARS.len()).div_ceil(highest) - 1] })
+            .collect();
+
+        // Nearest-rank percentile, in milliseconds.
+        let percentile = |p: f64| {
+            let rank = ((p / 100.0 * sorted.len() as f64).ceil() as usize).clamp(1, sorted.len());
+            sorted[rank - 1] as f64 / freq * 1000.0
+        };
+        writeln!(
+            out,
+            "    | {} | p50={:.prec$}ms p95={:.prec$}ms p99={:.prec$}ms",
+            bars,
+            percentile(50.0),
+            percentile(95.0),
+            percentile(99.0),
+            prec = self.precision,
+        )
+    }
+
+    fn write_totals<W: fmt::Write>(&self, out: &mut W, total_duration: f64, freq: f64) -> fmt::Result {
+        let anchors = &self.anchors[1..];
+        let total_exclusive = self.total_exclusive_ticks();
+        let total_calls: usize = anchors.iter().map(|a| a.calls).sum();
+        let self_elapsed = total_exclusive as f64 / freq;
+        let total_bytes = self.bytes_total();
+        let gb = (1024 * 1024 * 1024) as f64;
+        let throughput_str = if total_bytes != 0 {
+            format!(
+                " bytes={} throughput={}",
+                format_bytes(total_bytes),
+                throughput(total_bytes as f64 / gb, total_duration, self.precision),
+            )
+        } else {
+            String::new()
+        };
+        writeln!(
+            out,
+            "TOTAL[{}] - self={:.prec$}ms ({}){}",
+            total_calls,
+            self_elapsed * 1000.0,
+            percentage(self_elapsed, self.percentage_denominator(total_duration, freq), self.precision),
+            throughput_str,
+            prec = self.precision,
+        )?;
+        if total_bytes != 0 {
+            writeln!(
+                out,
+                "Total data: {} at {}",
+                format_bytes(total_bytes),
+                throughput(total_bytes as f64 / gb, total_duration, self.precision),
+            )?;
+        }
+        if let Some(anchor) = self.hottest_anchor() {
+            writeln!(
+                out,
+                "Hot path: {} ({:.1}% of self time)",
+                self.display_name(anchor),
+                self.hot_path_ratio() * 100.0,
+            )?;
+        }
+        writeln!(out, "Profiler overhead: ~{}ns per block", Self::estimate_overhead_ns())?;
+
+        let contention = Self::lock_contention_count();
+        if contention != 0 {
+            writeln!(out, "Lock contention: {} times", contention)?;
+        }
+        if self.nesting_error_count != 0 {
+            writeln!(
+                out,
+                "Warning: {} blocks ended out of order, exclusive times are unreliable",
+                self.nesting_error_count,
+            )?;
+        }
+        if self.overflow_count != 0 {
+            writeln!(
+                out,
+                "Warning: {} anchor overflows (increase max_anchors or use fewer dynamic names)",
+                self.overflow_count,
+            )?;
+        }
+        #[cfg(feature = "source-locations")]
+        for anchor in anchors.iter().filter(|a| !a.other_source_locations.is_empty()) {
+            writeln!(
+                out,
+                "Warning: anchor '{}' has {} source locations — statistics are merged",
+                self.display_name(anchor),
+                anchor.other_source_locations.len() + 1,
+            )?;
+        }
+        if let Some(footer) = &self.footer {
+            writeln!(out, "{}", footer)?;
+        }
+
+        // With no block open, the exclusive times must add up to exactly
+        // what the top-level blocks took.
+        if cfg!(debug_assertions) && self.parent_id == 0 && total_exclusive != self.instrumented_ticks() {
+            eprintln!(
+                "pprof warning: exclusive times sum to {} ticks but top-level blocks took {} ticks",
+                total_exclusive,
+                self.instrumented_ticks(),
+            );
+        }
+        Ok(())
+    }
+    pub fn add_bytes(&mut self, anchor_id: usize, bytes: usize) {
+        self.anchors[anchor_id].bytes += bytes;
+    }
+
+    /// Adds `results` to the profiler as if their blocks had run, e.g. to
+    /// feed report writers known fixture data instead of real timings. Times
+    /// are taken from the millisecond fields. The call graph isn't part of
+    /// `results`, so every replayed anchor counts as a top-level block.
+    /// Percentages are still relative to the real elapsed time unless
+    /// [`PercentageBase::InstrumentedTime`] is set.
+    pub fn replay_as_synthetic_blocks(&mut self, results: &ProfileResults) {
+        let freq = self.freq();
+        let to_ticks = |ms: f64| (ms / 1000.0 * freq).round() as u64;
+        for result in &results.anchors {
+            let id = self.get_anchor_id(&result.name);
+            if id == 0 {
+                continue;
+            }
+            self.add_top_level_calls(id, result.calls, to_ticks(result.exclusive_ms), to_ticks(result.inclusive_ms));
+            let anchor = &mut self.anchors[id];
+            anchor.bytes += result.bytes;
+            #[cfg(feature = "timeline")]
+            {
+                anchor.first_start_ns = result.first_start_ns;
+                anchor.last_end_ns = result.last_end_ns;
+            }
+            if result.comment.is_some() {
+                anchor.comment.clone_from(&result.comment);
+            }
+            anchor.custom_metrics.extend(result.custom_metrics.iter().map(|(k, &v)| (k.clone(), v)));
+            #[cfg(feature = "atomic-stats")]
+            self.publish_atomic_stats(&[id]);
+        }
+    }
+
+    /// Records one top-level call of the anchor `anchor_id` that took
+    /// `elapsed`. Blocks always end in the global profiler, so this is how
+    /// other profilers, such as one made with [`Profiler::fork`], get data.
+    pub fn record_call(&mut self, anchor_id: usize, elapsed: Duration) {
+        if anchor_id == 0 {
+            return;
+        }
+        let ticks = (elapsed.as_secs_f64() * self.freq()).round() as u64;
+        self.add_top_level_calls(anchor_id, 1, ticks, ticks);
+        #[cfg(feature = "atomic-stats")]
+        self.publish_atomic_stats(&[anchor_id]);
+    }
+
+    // Adds `calls` calls with the given total ticks to an anchor, as if they
+    // were made from the top level.
+    fn add_top_level_calls(&mut self, id: usize, calls: usize, exclusive: u64, inclusive: u64) {
+        self.anchors[0].elapsed_exclusive = self.anchors[0].elapsed_exclusive.wrapping_sub(exclusive);
+        let anchor = &mut self.anchors[id];
+        anchor.elapsed_exclusive = anchor.elapsed_exclusive.wrapping_add(exclusive);
+        anchor.elapsed_inclusive += inclusive;
+        anchor.calls += calls;
+        let edge = self.edges.entry((0, id)).or_default();
+        edge.calls += calls;
+        edge.elapsed_inclusive += inclusive;
+        self.max_depth = self.max_depth.max(1);
+    }
+
+    /// Looks up (or registers) the anchor named `name`, credits it with
+    /// `bytes` for the throughput column and starts a block of it, all under
+    /// the one lock the caller already holds. Unsampled blocks add no bytes.
+    #[cfg_attr(feature = "source-locations", track_caller)]
+    pub fn start_block_with_bytes(&mut self, name: &str, bytes: u64) -> Block {
+        if !sample() {
+            return Block::skipped();
+        }
+        let id = self.get_anchor_id(name);
+        self.add_bytes(id, bytes as usize);
+        self.begin_block(id)
+    }
+
+    // Makes the totals of the given anchors visible to `read_anchor_atomic`.
+    #[cfg(feature = "atomic-stats")]
+    #[inline]
+    fn publish_atomic_stats(&mut self, ids: &[usize]) {
+        let freq = self.freq();
+        for &id in ids {
+            if id != 0 {
+                self.atomic_stats.publish(id, &self.anchors[id], freq);
+            }
+        }
+    }
+
+    /// Clears the accumulated statistics of every anchor and restarts the
+    /// timer. Anchor names and IDs are kept, so existing IDs stay valid.
+    pub fn reset(&mut self) {
+        for anchor in &mut self.anchors {
+            anchor.clear_stats();
+        }
+        self.edges.clear();
+        self.drop_count = 0;
+        self.nesting_error_count = 0;
+        self.overflow_count = 0;
+        if let Some(baseline) = &mut self.delta_baseline {
+            baseline.clear();
+        }
+        self.max_depth = self.current_depth;
+        #[cfg(feature = "atomic-stats")]
+        self.atomic_stats.clear();
+        self.restart_timer();
+    }
+
+    /// Clears the accumulated statistics of the anchor named `name` only,
+    /// e.g. after a warmup call, along with the call graph edges into it.
+    /// Returns whether the anchor exists.
+    pub fn reset_anchor(&mut self, name: &str) -> bool {
+        let Some(id) = self.anchors[1..].iter().position(|a| a.name == name).map(|i| i + 1) else {
+            return false;
+        };
+        self.anchors[id].clear_stats();
+        self.edges.retain(|&(_, child), _| child != id);
+        #[cfg(feature = "atomic-stats")]
+        self.publish_atomic_stats(&[id]);
+        true
+    }
+
+    fn restart_timer(&mut self) {
+        self.start = Instant::now();
+        self.started_at = SystemTime::now();
+    }
+
+    /// An 8-digit hex ID derived from when the profiler was last reset, shown
+    /// in the report header so all lines of one report can be found among
+    /// other logs. The same profiler keeps its ID until the next reset.
+    pub fn generate_report_id(&self) -> String {
+        let nanos = self.started_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+        format!("{:08x}", (nanos ^ (nanos >> 32)) as u32)
+    }
+
+    /// Returns a copy of the profiler holding everything recorded so far and
+    /// then resets this one, like [`Profiler::reset`]. Both happen under the
+    /// same lock, so no block can land in between; call it on a timer for
+    /// per-interval reports: `pprof::lock().clone_and_reset().print()`.
+    pub fn clone_and_reset(&mut self) -> Profiler {
+        let mut snapshot = self.clone();
+        snapshot.end = Some(Instant::now());
+        self.reset();
+        snapshot
+    }
+
+    /// Creates an independent profiler for a child task, with the same
+    /// anchors under the same IDs and the same settings as this one but no
+    /// statistics, so IDs from [`Profiler::get_anchor_id`] work in both and
+    /// known names need no new registration. The child takes data through
+    /// [`Profiler::record_call`], without locking the global profiler; add
+    /// it back with [`Profiler::merge_with`]:
+    /// `parent.merge_with(&child.results())`.
+    pub fn fork(&self) -> Profiler {
+        let mut child = self.clone();
+        for anchor in &mut child.anchors {
+            anchor.open = 0;
+        }
+        child.parent_id = 0;
+        child.current_depth = 0;
+        child.last_print_time = None;
+        child.delta_baseline = None;
+        child.end = None;
+        child.reset();
+        child
+    }
+
+    /// Adds `results`, e.g. those of a profiler made with
+    /// [`Profiler::fork`], to this profiler's statistics, matching anchors
+    /// by name. Works like [`Profiler::replay_as_synthetic_blocks`], so the
+    /// merged anchors count as top-level blocks.
+    pub fn merge_with(&mut self, results: &ProfileResults) {
+        self.replay_as_synthetic_blocks(results);
+    }
+
+    /// How long ago [`Profiler::print`] last ran, `None` if it never did.
+    /// Reports show this as the interval their statistics accumulated over.
+    pub fn elapsed_since_last_print(&self) -> Option<Duration> {
+        self.last_print_time.map(|time| time.elapsed())
+    }
+
+    // Wall time covered by the profile.
+    fn elapsed(&self) -> Duration {
+        self.end.unwrap_or_else(Instant::now) - self.start
+    }
+
+    /// Removes every anchor without recorded calls, e.g. those left behind
+    /// by dynamically named blocks after a [`Profiler::reset`].
+    ///
+    /// This renumbers the remaining anchors, so IDs obtained from
+    /// [`Profiler::get_anchor_id`] before the call are invalid afterwards.
+    /// Open blocks hold such IDs, so while any is open this only prints a
+    /// warning and removes nothing.
+    pub fn trim_inactive_anchors(&mut self) {
+        if self.current_depth != 0 {
+            eprintln!("pprof warning: not trimming anchors with {} blocks open", self.current_depth);
+            return;
+        }
+        let mut new_ids = Vec::with_capacity(self.anchors.len());
+        let mut next_id = 0;
+        for (id, anchor) in self.anchors.iter().enumerate() {
+            if id == 0 || anchor.calls != 0 {
+                new_ids.push(Some(next_id));
+                next_id += 1;
+            } else {
+                new_ids.push(None);
+            }
+        }
+
+        let mut id = 0;
+        self.anchors.retain(|_| {
+            id += 1;
+            new_ids[id - 1].is_some()
+        });
+        self.edges = std::mem::take(&mut self.edges)
+            .into_iter()
+            .filter_map(|((parent, child), edge)| Some(((new_ids[parent]?, new_ids[child]?), edge)))
+            .collect();
+    }
+
+    /// Returns a copy of the profiler in which anchors whose names
+    /// `template_fn` maps to the same template are merged into one anchor
+    /// named after it, with their statistics and call graph edges added up.
+    /// For dynamically named blocks, e.g. turning `/api/user/1` and
+    /// `/api/user/2` into `/api/user`. Total time stops at the call, as with
+    /// [`Profiler::clone_and_reset`].
+    pub fn compact(&self, template_fn: impl Fn(&str) -> String) -> Profiler {
+        let mut compacted = self.clone();
+        compacted.end = Some(self.end.unwrap_or_else(Instant::now));
+        compacted.anchors.truncate(1);
+        compacted.edges.clear();
+        compacted.parent_id = 0;
+        compacted.current_depth = 0;
+
+        let mut ids_by_template: HashMap<String, usize> = HashMap::new();
+        let mut new_ids = vec![0; self.anchors.len()];
+        for (id, anchor) in self.anchors.iter().enumerate().skip(1) {
+            let template = template_fn(&anchor.name);
+            let new_id = match ids_by_template.get(&template) {
+                Some(&new_id) => {
+                    compacted.anchors[new_id].absorb(anchor);
+                    new_id
+                }
+                None => {
+                    let mut merged = anchor.clone();
+                    merged.name = Cow::Owned(template.clone());
+                    merged.open = 0;
+                    compacted.anchors.push(merged);
+                    ids_by_template.insert(template, compacted.anchors.len() - 1);
+                    compacted.anchors.len() - 1
+                }
+            };
+            new_ids[id] = new_id;
+        }
+        for (&(parent, child), edge) in &self.edges {
+            let merged = compacted.edges.entry((new_ids[parent], new_ids[child])).or_default();
+            merged.calls += edge.calls;
+            merged.elapsed_inclusive += edge.elapsed_inclusive;
+        }
+        compacted
+    }
+
+    /// Renames every anchor whose name is a key in `map` to the mapped value,
+    /// e.g. to give auto-generated names readable ones before printing. When
+    /// the new name is already taken, by another renamed anchor or an
+    /// existing one, the anchor becomes `name (2)`, `name (3)` and so on.
+    /// Meant for after the run: blocks started later under an old name get
+    /// a new anchor.
+    pub fn apply_name_map(&mut self, map: &HashMap<String, String>) {
+        // Checked against the final names, so that swaps and chains like
+        // `a -> b, b -> c` don't count as conflicts.
+        let mut taken: HashSet<String> = self.anchors[1..]
+            .iter()
+            .filter(|a| !map.contains_key(a.name.as_ref()))
+            .map(|a| a.name.to_string())
+            .collect();
+        for anchor in &mut self.anchors[1..] {
+            let Some(new_name) = map.get(anchor.name.as_ref()) else {
+                continue;
+            };
+            let mut name = new_name.clone();
+            let mut n = 1;
+            while taken.contains(&name) {
+                n += 1;
+                name = format!("{} ({})", new_name, n);
+            }
+            taken.insert(name.clone());
+            anchor.name = Cow::Owned(name);
+        }
+    }
+
+    /// Renames every anchor named after a raw address, e.g. `0x4005d0` for
+    /// instrumented JIT code, to the function at that address in
+    /// `binary_path` and its source location, as in
+    /// `parse_header (src/parse.rs:42)`. Resolved with the `addr2line`
+    /// tool, which has to be on the `PATH`. Addresses that don't resolve, or
+    /// that resolve to the name of another anchor, are left alone.
+    #[cfg(feature = "addr2line")]
+    pub fn annotate_with_symbol_names(&mut self, binary_path: &Path) -> io::Result<()> {
+        let ids: Vec<usize> = (1..self.anchors.len()).filter(|&id| is_hex_address(&self.anchors[id].name)).collect();
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let output = std::process::Command::new("addr2line")
+            .args(["-f", "-C", "-e"])
+            .arg(binary_path)
+            .args(ids.iter().map(|&id| self.anchors[id].name.as_ref()))
+            .output()?;
+        if !output.status.success() {
+            let message = String::from_utf8_lossy(&output.stderr);
+            return Err(io::Error::other(format!("addr2line failed: {}", message.trim())));
+        }
+        // Two lines per address: the function, then `file:line`.
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let mut lines = stdout.lines();
+        for id in ids {
+            let (Some(function), Some(location)) = (lines.next(), lines.next()) else {
+                break;
+            };
+            if function == "??" {
+                continue;
+            }
+            let name = if location.starts_with("??") {
+                function.to_string()
+            } else {
+                format!("{} ({})", function, location)
+            };
+            if self.find_anchor_id(&name).is_none() {
+                self.anchors[id].name = Cow::Owned(name);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Formats the same report as [`Profiler::print`].
+impl fmt::Display for Profiler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_report(f)
+    }
+}
+
+#[cfg(feature = "rdtsc")]
+macro_rules! get_cpu_timer {
+    () => {{
+        unsafe {
+            core::arch::x86_64::_rdtsc()
+        }
+    }}
+}
+
+#[cfg(not(feature = "rdtsc"))]
+pub struct Block {
+    start: Instant,
+    anchor_id: usize,
+    parent_id: usize,
+    old_elapsed_inclusive: u64,
+    // The anchor's exclusive time when the block started.
+    old_elapsed_exclusive: u64,
+    // The innermost open block's anchor when this one started, which
+    // differs from `parent_id` for blocks with an explicit parent.
+    outer_id: usize,
+    recorded: bool,
+    #[cfg(feature = "block-payload")]
+    payload: u64,
+    // Allocation count and bytes of the thread when the block started.
+    #[cfg(feature = "counting-allocator")]
+    start_allocations: (u64, u64),
+    // The anchor's exclusive allocation count and bytes when the block
+    // started.
+    #[cfg(feature = "counting-allocator")]
+    old_allocations: (u64, u64),
+}
+
+#[cfg(feature = "rdtsc")]
+pub struct Block {
+    start: u64,
+    // Whether `start` is in nanoseconds rather than TSC ticks.
+    wall_clock: bool,
+    anchor_id: usize,
+    parent_id: usize,
+    old_elapsed_inclusive: u64,
+    // The anchor's exclusive time when the block started.
+    old_elapsed_exclusive: u64,
+    // The innermost open block's anchor when this one started, which
+    // differs from `parent_id` for blocks with an explicit parent.
+    outer_id: usize,
+    recorded: bool,
+    #[cfg(feature = "block-payload")]
+    payload: u64,
+    // Allocation count and bytes of the thread when the block started.
+    #[cfg(feature = "counting-allocator")]
+    start_allocations: (u64, u64),
+    // The anchor's exclusive allocation count and bytes when the block
+    // started.
+    #[cfg(feature = "counting-allocator")]
+    old_allocations: (u64, u64),
+}
+
+// Creating and dropping a `Block` happens on every profiled call, so these
+// methods are marked `#[inline]` to let them fold into the caller; the timer
+// read itself is forced inline so that no call overhead lands between the
+// two timestamps.
+impl Block {
+    #[inline]
+    pub fn new(anchor_id: usize, parent_id: usize, old_elapsed_inclusive: u64) -> Self {
+        Self::with_clock(anchor_id, parent_id, old_elapsed_inclusive, false)
+    }
+
+    #[cfg(not(feature = "rdtsc"))]
+    #[inline]
+    fn with_clock(anchor_id: usize, parent_id: usize, old_elapsed_inclusive: u64, _wall_clock: bool) -> Self {
+        Self {
+            start: Instant::now(),
+            anchor_id,
+            parent_id,
+            old_elapsed_inclusive,
+            old_elapsed_exclusive: 0,
+            outer_id: parent_id,
+            recorded: true,
+            #[cfg(feature = "block-payload")]
+            payload: 0,
+            #[cfg(feature = "counting-allocator")]
+            start_allocations: alloc::allocations(),
+            #[cfg(feature = "counting-allocator")]
+            old_allocations: (0, 0),
+        }
+    }
+
+    #[cfg(feature = "rdtsc")]
+    #[inline]
+    fn with_clock(anchor_id: usize, parent_id: usize, old_elapsed_inclusive: u64, wall_clock: bool) -> Self {
+        Self {
+            start: read_timer(wall_clock),
+            wall_clock,
+            anchor_id,
+            parent_id,
+            old_elapsed_inclusive,
+            old_elapsed_exclusive: 0,
+            outer_id: parent_id,
+            recorded: true,
+            #[cfg(feature = "block-payload")]
+            payload: 0,
+            #[cfg(feature = "counting-allocator")]
+            start_allocations: alloc::allocations(),
+            #[cfg(feature = "counting-allocator")]
+            old_allocations: (0, 0),
+        }
+    }
+
+    #[cfg(not(feature = "rdtsc"))]
+    #[inline(always)]
+    pub fn elapsed(&self) -> u64 {
+        self.start.elapsed().as_nanos() as u64
+    }
+
+    #[cfg(feature = "rdtsc")]
+    #[inline(always)]
+    pub fn elapsed(&self) -> u64 {
+        read_timer(self.wall_clock) - self.start
+    }
+
+    // A block that is dropped without touching the profiler.
+    fn skipped() -> Self {
+        // Not built with `..Self::new(..)`: that would also drop the
+        // temporary block and record it.
+        let mut block = Self::new(0, 0, 0);
+        block.recorded = false;
+        block
+    }
+
+    #[inline]
+    #[cfg_attr(feature = "source-locations", track_caller)]
+    pub fn from_id(id: usize) -> Self {
+        if lock_held() || !sample() {
+            return Self::skipped();
+        }
+        lock_profiler().begin_block(id)
+    }
+
+    /// Like [`Block::from_id`], but attributes the block to the anchor
+    /// `parent_id` rather than to the innermost open block. For async or
+    /// manually scheduled code, where the open block is the scheduler's
+    /// and not the logical caller's. The parent's self time still pays for
+    /// the block, so a block of it should be open meanwhile, e.g. awaiting
+    /// the task. Blocks started inside this one are still its children.
+    #[inline]
+    #[cfg_attr(feature = "source-locations", track_caller)]
+    pub fn from_id_with_parent(anchor_id: usize, parent_id: usize) -> Self {
+        if lock_held() || !sample() {
+            return Self::skipped();
+        }
+        lock_profiler().begin_block_with_parent(anchor_id, parent_id)
+    }
+
+    /// Looks up (or registers) the anchor and starts the block under a
+    /// single lock, without allocating once the anchor exists.
+    #[inline]
+    #[cfg_attr(feature = "source-locations", track_caller)]
+    pub fn from_static_name(name: &'static str) -> Self {
+        if lock_held() || !sample() {
+            return Self::skipped();
+        }
+        let mut p = lock_profiler();
+        let id = p.get_static_anchor_id(name);
+        p.begin_block(id)
+    }
+
+    #[inline]
+    #[cfg_attr(feature = "source-locations", track_caller)]
+    pub fn from_name(name: &str) -> Self {
+        if lock_held() || !sample() {
+            return Self::skipped();
+        }
+        let mut p = lock_profiler();
+        let id = p.get_anchor_id(name);
+        p.begin_block(id)
+    }
+
+    /// Like [`Block::from_id_with_parent`], looking up (or registering) the
+    /// anchor under the same lock.
+    #[inline]
+    #[cfg_attr(feature = "source-locations", track_caller)]
+    pub fn from_name_with_parent(name: &str, parent_id: usize) -> Self {
+        if lock_held() || !sample() {
+            return Self::skipped();
+        }
+        let mut p = lock_profiler();
+        let id = p.get_anchor_id(name);
+        p.begin_block_with_parent(id, parent_id)
+    }
+
+    /// Starts a block that processed `bytes` bytes, see
+    /// [`Profiler::start_block_with_bytes`].
+    #[inline]
+    #[cfg_attr(feature = "source-locations", track_caller)]
+    pub fn from_name_with_bytes(name: &str, bytes: u64) -> Self {
+        if lock_held() {
+            return Self::skipped();
+        }
+        lock_profiler().start_block_with_bytes(name, bytes)
+    }
+}
+
+impl Block {
+    /// Attaches a number of the caller's choosing to the block, e.g. the rows
+    /// a query returned, for the callback of [`Profiler::on_anchor_update`]:
+    /// `block!("query").with_payload(row_count)`.
+    #[cfg(feature = "block-payload")]
+    #[inline]
+    pub fn with_payload(mut self, payload: u64) -> Self {
+        self.payload = payload;
+        self
+    }
+
+    #[inline]
+    fn ended(&self) -> EndedBlock {
+        EndedBlock {
+            anchor_id: self.anchor_id,
+            parent_id: self.parent_id,
+            old_elapsed_inclusive: self.old_elapsed_inclusive,
+            old_elapsed_exclusive: self.old_elapsed_exclusive,
+            #[cfg(feature = "counting-allocator")]
+            old_allocations: self.old_allocations,
+            outer_id: self.outer_id,
+            elapsed: self.elapsed(),
+            #[cfg(feature = "block-payload")]
+            payload: self.payload,
+            #[cfg(feature = "counting-allocator")]
+            allocations: {
+                let (count, bytes) = alloc::allocations();
+                // Wrapping: a block ending on another thread than it started
+                // on reads that thread's counters, which may be behind.
+                (count.wrapping_sub(self.start_allocations.0), bytes.wrapping_sub(self.start_allocations.1))
+            },
+            #[cfg(feature = "timeline")]
+            ended_at: Instant::now(),
+        }
+    }
+}
+
+impl fmt::Debug for Block {
+    // Names come from the global profiler, which may be locked (possibly by
+    // this very thread), so this doesn't wait for it.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (anchor, parent) = match PROFILER.try_lock() {
+            Ok(p) => (
+                p.anchors.get(self.anchor_id).map_or_else(String::new, |a| a.name.to_string()),
+                p.anchors.get(self.parent_id).map_or_else(String::new, |a| a.name.to_string()),
+            ),
+            Err(_) => ("<locked>".to_string(), "<locked>".to_string()),
+        };
+        #[cfg(not(feature = "rdtsc"))]
+        let elapsed_ns = self.elapsed();
+        #[cfg(feature = "rdtsc")]
+        let elapsed_ns =
+            if self.wall_clock { self.elapsed() } else { (self.elapsed() as f64 / get_duration_freq() * 1e9) as u64 };
+        f.debug_struct("Block")
+            .field("anchor", &anchor)
+            .field("elapsed_so_far", &format_args!("{}ns", elapsed_ns))
+            .field("parent", &parent)
+            .finish()
+    }
+}
+
+impl Drop for Block {
+    #[inline]
+    fn drop(&mut self) {
+        if !self.recorded {
+            return;
+        }
+        let ended = self.ended();
+        if lock_held() {
+            PENDING.with(|pending| pending.borrow_mut().push(ended));
+            return;
+        }
+        lock_profiler().end_block(ended);
+    }
+}
+
+// Anchor counts `iter_sorted_by_self_time` sorts without a heap allocation.
+const INLINE_SORT_LEN: usize = 64;
+
+// Anchor IDs in the order `iter_sorted_by_self_time` yields them. The inline
+// variant is large on purpose: boxing it would defeat its point.
+#[allow(clippy::large_enum_variant)]
+enum SortedIds {
+    Inline([usize; INLINE_SORT_LEN], std::ops::Range<usize>),
+    Heap(std::vec::IntoIter<usize>),
+}
+
+impl Iterator for SortedIds {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        match self {
+            SortedIds::Inline(ids, range) => range.next().map(|i| ids[i]),
+            SortedIds::Heap(ids) => ids.next(),
+        }
+    }
+}
+
+// An anchor's result, ordered by self time, for `iter_by_self_time`.
+struct OrdAnchorResult(AnchorResult);
+
+impl PartialEq for OrdAnchorResult {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for OrdAnchorResult {}
+
+impl PartialOrd for OrdAnchorResult {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for OrdAnchorResult {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.exclusive_ms.total_cmp(&other.0.exclusive_ms)
+    }
+}
+
+// The iterator behind `iter_by_self_time`; the heap is built on first use.
+struct BySelfTime<'a> {
+    profiler: &'a Profiler,
+    heap: Option<BinaryHeap<OrdAnchorResult>>,
+}
+
+impl Iterator for BySelfTime<'_> {
+    type Item = AnchorResult;
+
+    fn next(&mut self) -> Option<AnchorResult> {
+        let profiler = self.profiler;
+        let heap = self.heap.get_or_insert_with(|| {
+            let freq = profiler.freq();
+            profiler.anchors[1..].iter().map(|a| OrdAnchorResult(a.result(freq))).collect()
+        });
+        heap.pop().map(|r| r.0)
+    }
+}
+
+// A finished block whose statistics still have to be added to the profiler.
+struct EndedBlock {
+    anchor_id: usize,
+    parent_id: usize,
+    old_elapsed_inclusive: u64,
+    old_elapsed_exclusive: u64,
+    #[cfg(feature = "counting-allocator")]
+    old_allocations: (u64, u64),
+    outer_id: usize,
+    elapsed: u64,
+    #[cfg(feature = "block-payload")]
+    payload: u64,
+    // Allocation count and bytes made while the block was open.
+    #[cfg(feature = "counting-allocator")]
+    allocations: (u64, u64),
+    #[cfg(feature = "timeline")]
+    ended_at: Instant,
+}
+
+/// What `block!(:skip, ...)` expands to: a guard that measures nothing, for
+/// blocks too hot to profile even when profiling is enabled. The block's
+/// arguments are not evaluated.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoOpBlock;
+
+// `part` as a percentage of `total`, or `N/A` when no time has passed yet.
+fn percentage(part: f64, total: f64, precision: usize) -> String {
+    if total == 0.0 {
+        return "N/A".to_string();
+    }
+    format!("{:.prec$}%", part / total * 100.0, prec = precision)
+}
+
+// Gigabytes per second, or `N/A` when no time has passed yet.
+fn throughput(gigabytes: f64, seconds: f64, precision: usize) -> String {
+    if seconds == 0.0 {
+        return "N/A".to_string();
+    }
+    format!("{:.prec$} GB/s", gigabytes / seconds, prec = precision)
+}
+
+// `500 B`, `1.95 KB`, `3.00 MB`, ... in powers of 1024.
+fn format_bytes(n: u64) -> String {
+    const UNITS: [&str; 3] = ["KB", "MB", "GB"];
+    if n < 1024 {
+        return format!("{} B", n);
+    }
+    let mut value = n as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.2} {}", value, UNITS[unit])
+}
+
+// `2m 5.000s`, `1d 3h 0m 12.500s`, ...: the larger units only from the
+// first one that isn't zero.
+fn format_duration_human(ms: f64) -> String {
+    let seconds = ms / 1000.0;
+    let whole = seconds as u64;
+    let (days, hours, minutes) = (whole / 86_400, whole / 3_600 % 24, whole / 60 % 60);
+    let seconds = seconds - (whole - whole % 60) as f64;
+    let mut out = String::new();
+    if days != 0 {
+        let _ = write!(out, "{}d ", days);
+    }
+    if days != 0 || hours != 0 {
+        let _ = write!(out, "{}h ", hours);
+    }
+    if days != 0 || hours != 0 || minutes != 0 {
+        let _ = write!(out, "{}m ", minutes);
+    }
+    let _ = write!(out, "{:.3}s", seconds);
+    out
+}
+
+// Reports are formatted into a buffer first and then written in one go under
+// the stream's lock, so reports printed from several threads at once (say, a
+// signal handler and the exit path) never interleave.
+fn write_locked(mut out: impl io::Write, report: &str) {
+    let _ = out.write_all(report.as_bytes());
+    let _ = out.flush();
+}
+
+// `report` without the color codes reports use.
+fn strip_colors(report: &str) -> String {
+    [ANSI_RED, ANSI_YELLOW, ANSI_RESET].iter().fold(report.to_string(), |report, code| report.replace(code, ""))
+}
+
+// Makes a name safe to print: ANSI escape sequences are dropped and other
+// control characters are shown escaped, so names built from untrusted input
+// can't mess with the terminal or split a report line.
+fn sanitize_name(name: &str) -> Cow<'_, str> {
+    if !name.chars().any(char::is_control) {
+        return Cow::Borrowed(name);
+    }
+    let mut sanitized = String::with_capacity(name.len());
+    let mut chars = name.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1b' => {
+                // A CSI sequence (`ESC [ ... m` and friends) ends with a
+                // byte in `@`..=`~`.
+                if chars.next_if_eq(&'[').is_some() {
+                    while chars.next().is_some_and(|c| !('@'..='~').contains(&c)) {}
+                }
+            }
+            '\n' => sanitized.push_str("\\n"),
+            '\r' => sanitized.push_str("\\r"),
+            '\t' => sanitized.push_str("\\t"),
+            c if c.is_control() => sanitized.extend(c.escape_default()),
+            c => sanitized.push(c),
+        }
+    }
+    Cow::Owned(sanitized)
+}
+
+// The module part of an anchor name, e.g. `crate::io` for
+// `crate::io::read[chunk]`. A `[label]` or `@file:line` suffix never counts
+// towards it.
+fn module_path(name: &str) -> &str {
+    let function = name.split(['[', '@']).next().unwrap_or(name);
+    function.rfind("::").map_or("", |i| &function[..i])
+}
+
+// Whether `name` is a raw address like `0x4005d0`.
+#[cfg(feature = "addr2line")]
+fn is_hex_address(name: &str) -> bool {
+    name.strip_prefix("0x").is_some_and(|digits| !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+// An anchor name without its module path, e.g. `read[chunk]` for
+// `crate::io::read[chunk]`.
+fn function_name(name: &str) -> &str {
+    match module_path(name) {
+        "" => name,
+        module => &name[module.len() + 2..],
+    }
+}
+
+#[cfg(not(feature = "rdtsc"))]
+fn get_duration_freq() -> f64 {
+    1_000_000_000.0
+}
+
+// TSC ticks, or nanoseconds since the first wall-clock read.
+#[cfg(feature = "rdtsc")]
+#[inline(always)]
+fn read_timer(wall_clock: bool) -> u64 {
+    if wall_clock {
+        static EPOCH: std::sync::OnceLock<Instant> = std::sync::OnceLock::new();
+        EPOCH.get_or_init(Instant::now).elapsed().as_nanos() as u64
+    } else {
+        get_cpu_timer!()
+    }
+}
+
+// Calibration sleeps for 100ms and only runs once, when the first profiler is
+// created, so keep it out of the way of the hot path.
+#[cfg(feature = "rdtsc")]
+#[cold]
+fn get_duration_freq() -> f64 {
+    static FREQ: std::sync::OnceLock<f64> = std::sync::OnceLock::new();
+    *FREQ.get_or_init(|| {
+        let start = get_cpu_timer!();
+        thread::sleep(Duration::from_millis(100));
+        let end = get_cpu_timer!();
+        (end - start) as f64 * 10.0
+    })
+}
+
+#[macro_export]
+macro_rules! fn_name {
+    () => {{
+        fn f() {}
+        fn type_name_of<T>(_: T) -> &'static str {
+            std::any::type_name::<T>()
+        }
+        static NAME: std::sync::OnceLock<std::borrow::Cow<'static, str>> = std::sync::OnceLock::new();
+        let name: &'static str = NAME.get_or_init(|| pprof::clean_fn_name(type_name_of(f).strip_suffix("::f").unwrap()));
+        name
+    }};
+}
+
+/// Makes a `type_name`-derived function path readable: `::{{closure}}`
+/// segments become a single `[closure]` suffix and `::{{impl}}` segments are
+/// dropped. Used by [`fn_name!`], which caches the result per call site.
+#[doc(hidden)]
+pub fn clean_fn_name(name: &'static str) -> Cow<'static, str> {
+    if !name.contains("{{") {
+        return Cow::Borrowed(name);
+    }
+    let mut cleaned = String::with_capacity(name.len());
+    for segment in name.split("::") {
+        match segment {
+            "{{closure}}" => {
+                if !cleaned.ends_with("[closure]") {
+                    cleaned.push_str("[closure]");
+                }
+            }
+            "{{impl}}" => {}
+            _ => {
+                if !cleaned.is_empty() {
+                    cleaned.push_str("::");
+                }
+                cleaned.push_str(segment);
+            }
+        }
+    }
+    Cow::Owned(cleaned)
+}
+
+#[macro_export]
+macro_rules! block {
+    (:skip $($args:tt)*) => {
+        pprof::NoOpBlock
+    };
+    () => {{
+        pprof::Block::from_static_name(pprof::fn_name!())
+    }};
+    ($name:literal) => {{
+        static NAME: std::sync::OnceLock<String> = std::sync::OnceLock::new();
+        let fn_name = pprof::fn_name!();
+        pprof::Block::from_static_name(NAME.get_or_init(|| format!("{}[{}]", fn_name, $name)))
+    }};
+    ($name:expr) => {{
+        pprof::Block::from_name(&format!("{}[{}]", pprof::fn_name!(), $name))
+    }};
+    ($name:expr, parent: $parent:expr) => {{
+        pprof::Block::from_name_with_parent(&format!("{}[{}]", pprof::fn_name!(), $name), $parent)
+    }};
+    ($name:expr, $bytes:expr) => {{
+        pprof::Block::from_name_with_bytes(&format!("{}[{}]", pprof::fn_name!(), $name), $bytes as u64)
+    }}
+}
+
+/// Starts a block named exactly `name`, without the enclosing function's
+/// path that [`block!`] puts in front. Inside closures, and so in the
+/// common `fn make() -> impl Fn()` pattern, that path is the closure's and
+/// ends in `[closure]`; naming the block explicitly keeps the report clean.
+#[macro_export]
+macro_rules! block_named {
+    ($name:literal) => {{
+        pprof::Block::from_static_name($name)
+    }};
+    ($name:expr) => {{
+        pprof::Block::from_name(&$name)
+    }};
+}
+
+/// Registers the anchor [`block!`] would use for the same arguments and
+/// returns its ID, without starting a block. Useful for adding bytes bit by
+/// bit, e.g. while streaming: `pprof::lock().add_bytes(id, chunk.len())`.
+/// See [`anchor_id`] for use while the profiler is locked.
+#[macro_export]
+macro_rules! block_id {
+    () => {{
+        pprof::static_anchor_id(pprof::fn_name!())
+    }};
+    ($name:expr) => {{
+        pprof::anchor_id(&format!("{}[{}]", pprof::fn_name!(), $name))
+    }};
+}
+
+/// Sets a custom metric on an anchor, see [`Profiler::add_custom_metric`]:
+/// `block_metric!(id, "cache_hit_rate", hits as f64 / lookups as f64)`.
+#[macro_export]
+macro_rules! block_metric {
+    ($anchor_id:expr, $key:expr, $value:expr) => {
+        pprof::add_custom_metric_by_id($anchor_id, $key, $value)
+    };
+}
+
+/// Like [`block!`], but the anchor name ends in `@<file>:<line>`, so every
+/// call site gets its own anchor even when several share a name.
+#[macro_export]
+macro_rules! block_site {
+    () => {{
+        static NAME: std::sync::OnceLock<String> = std::sync::OnceLock::new();
+        let fn_name = pprof::fn_name!();
+        pprof::Block::from_static_name(NAME.get_or_init(|| format!("{}@{}:{}", fn_name, file!(), line!())))
+    }};
+    ($name:literal) => {{
+        static NAME: std::sync::OnceLock<String> = std::sync::OnceLock::new();
+        let fn_name = pprof::fn_name!();
+        pprof::Block::from_static_name(
+            NAME.get_or_init(|| format!("{}[{}]@{}:{}", fn_name, $name, file!(), line!())),
+        )
+    }};
+    ($name:expr) => {{
+        pprof::Block::from_name(&format!("{}[{}]@{}:{}", pprof::fn_name!(), $name, file!(), line!()))
+    }};
+}
+
+/// Locks the global profiler. Prefer this over locking [`PROFILER`]
+/// directly: profiled code running on the same thread while the guard is
+/// held is handled gracefully instead of deadlocking.
+///
+/// # Panics
+///
+/// If the calling thread already holds the guard.
+pub fn lock() -> ProfilerGuard {
+    lock_profiler()
+}
+
+/// Runs `f` inside a block named `name` and returns its result: the
+/// closure-based equivalent of [`block!`], for code that would rather not
+/// use macros or that profiles closures kept in variables. Unlike `block!`,
+/// the name is used as is, without the enclosing function's path. If `f`
+/// panics, the block still ends and the panic carries on.
+///
+/// ```
+/// let sum = pprof::with("sum", || (1..=10).sum::<u32>());
+/// assert_eq!(sum, 55);
+/// ```
+#[cfg_attr(feature = "source-locations", track_caller)]
+pub fn with<F, R>(name: &str, f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let _block = Block::from_name(name);
+    f()
+}
+
+/// Like [`with`], for a block that processed `bytes` bytes, see
+/// [`scope_bytes`].
+#[cfg_attr(feature = "source-locations", track_caller)]
+pub fn with_bytes<F, R>(name: &str, bytes: u64, f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    scope_bytes(name, bytes, f)
+}
+
+/// Runs `f` inside a block named `name` that processed `bytes` bytes, so the
+/// report shows its throughput: `pprof::scope_bytes("read", buf.len() as u64,
+/// || file.read_exact(&mut buf))`.
+#[cfg_attr(feature = "source-locations", track_caller)]
+pub fn scope_bytes<F, R>(name: &str, bytes: u64, f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let _block = Block::from_name_with_bytes(name, bytes);
+    f()
+}
+
+/// Starts a fresh profiling session.
+///
+/// Note: this clears all statistics gathered so far, not just the timer.
+/// Earlier versions only restarted the timer, so a second `init()` call
+/// kept the stale data of the previous pass. Use [`reset_timer_only`] to
+/// restore the old behavior and accumulate across several `init()` calls.
+pub fn init() {
+    lock_profiler().reset();
+}
+
+/// Clears the statistics of one anchor, see [`Profiler::reset_anchor`].
+pub fn reset_anchor(name: &str) -> bool {
+    lock_profiler().reset_anchor(name)
+}
+
+/// Restarts the profiling timer without touching the anchor statistics.
+pub fn reset_timer_only() {
+    lock_profiler().restart_timer();
+}
+
+pub fn print() {
+    lock_profiler().print();
+}
+
+/// Prints the call tree, see [`Profiler::print_tree`].
+pub fn print_tree() {
+    lock_profiler().print_tree();
+}
+
+/// Prints the report with a context line after the header, see
+/// [`Profiler::print_with_context`].
+pub fn print_with_context(context: &str) {
+    lock_profiler().print_with_context(context);
+}
+
+/// Prints the report if some anchor took longer than `threshold_ms`, see
+/// [`Profiler::print_if_slow`].
+pub fn print_if_slow(threshold_ms: f64) -> bool {
+    lock_profiler().print_if_slow(threshold_ms)
+}
+
+/// Saves the profile as gzip-compressed JSON, see
+/// [`Profiler::export_gzip_json`].
+#[cfg(feature = "gzip")]
+pub fn save_gzip_json(path: &std::path::Path) -> io::Result<()> {
+    let file = std::fs::File::create(path)?;
+    lock_profiler().export_gzip_json(io::BufWriter::new(file))
+}
+
+// Where `save_on_exit` writes the report, `None` for stdout.
+static EXIT_REPORT_PATH: Mutex<Option<PathBuf>> = Mutex::new(None);
+// Whether the exit report was written, so it isn't printed twice.
+static EXIT_REPORTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" {
+    fn atexit(cb: extern "C" fn()) -> std::os::raw::c_int;
+}
+
+#[cfg(unix)]
+extern "C" fn write_exit_report_at_exit() {
+    write_exit_report();
+}
+
+// Runs during process teardown or a panic, so it only waits a little for
+// the profiler, and not at all if this very thread holds it.
+fn write_exit_report() {
+    if EXIT_REPORTED.load(Ordering::SeqCst) || LOCK_HELD.try_with(Cell::get).unwrap_or(false) {
+        return;
+    }
+    let deadline = Instant::now() + Duration::from_millis(100);
+    let mut p = loop {
+        match PROFILER.try_lock() {
+            Ok(p) => break p,
+            Err(TryLockError::Poisoned(e)) => break e.into_inner(),
+            Err(TryLockError::WouldBlock) if Instant::now() < deadline => thread::sleep(Duration::from_millis(1)),
+            Err(TryLockError::WouldBlock) => return,
+        }
+    };
+    // Checked again under the lock, in case another thread just wrote it.
+    if EXIT_REPORTED.load(Ordering::SeqCst) {
+        return;
+    }
+    let path = EXIT_REPORT_PATH.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    match path {
+        Some(path) => {
+            let mut report = String::new();
+            let _ = p.write_report(&mut report);
+            if let Err(e) = std::fs::write(&path, p.uncolored_unless(&report, false).as_bytes()) {
+                eprintln!("pprof: failed to write {}: {}", path.display(), e);
+            }
+        }
+        None => p.print(),
+    }
+    EXIT_REPORTED.store(true, Ordering::SeqCst);
+}
+
+/// Prints the report when the process exits (returning from `main`, calling
+/// `std::process::exit` or a panic unwinding out of `main`), for programs
+/// that don't call [`print`] themselves. Writes it to `path` instead of
+/// stdout if given. With `panic = "abort"`, any panic prints it before the
+/// process aborts. Panics that are caught or only end another thread don't.
+/// Exits are only caught on Unix; elsewhere, panics on the main thread print
+/// the report, even if caught later. Calling it again changes the
+/// destination.
+pub fn save_on_exit(path: Option<&Path>) {
+    static REGISTERED: std::sync::Once = std::sync::Once::new();
+    *EXIT_REPORT_PATH.lock().unwrap_or_else(|e| e.into_inner()) = path.map(Path::to_path_buf);
+    REGISTERED.call_once(|| {
+        #[cfg(unix)]
+        // SAFETY: `atexit` only stores the function pointer.
+        unsafe {
+            atexit(write_exit_report_at_exit);
+        }
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            previous(info);
+            // Unwinding panics end the process only by leaving `main`,
+            // which the exit handler covers where there is one.
+            let ends_process = if cfg!(panic = "abort") {
+                true
+            } else {
+                cfg!(not(unix)) && thread::current().name() == Some("main")
+            };
+            if ends_process {
+                write_exit_report();
+            }
+        }));
+    });
+}
+
+/// Spawns a thread that prints the report to stderr every `interval_ms`
+/// milliseconds, until [`PeriodicReporter::stop`] is called.
+pub fn start_periodic_reporter(interval_ms: u64) -> PeriodicReporter {
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread = {
+        let stop = Arc::clone(&stop);
+        thread::spawn(move || {
+            let interval = Duration::from_millis(interval_ms);
+            loop {
+                // `stop()` unparks the thread, so it exits without waiting
+                // out the interval. Parking may also wake up spuriously.
+                let deadline = Instant::now() + interval;
+                while !stop.load(Ordering::Relaxed) && Instant::now() < deadline {
+                    thread::park_timeout(deadline.saturating_duration_since(Instant::now()));
+                }
+                if stop.load(Ordering::Relaxed) {
+                    return;
+                }
+                lock_profiler().eprint_report();
+            }
+        })
+    };
+    PeriodicReporter { stop, thread }
+}
+
+/// The reporter thread started by [`start_periodic_reporter`]. It keeps
+/// running when this handle is dropped.
+pub struct PeriodicReporter {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl PeriodicReporter {
+    /// Stops the reporter and waits for its thread to exit.
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        self.thread.thread().unpark();
+        let _ = self.thread.join();
+    }
+}
+
+pub fn freeze() -> FrozenProfiler {
+    lock_profiler().freeze()
+}
+
+pub fn anchor_count() -> usize {
+    lock_profiler().anchor_count()
+}
+
+pub fn anchor_exists(name: &str) -> bool {
+    lock_profiler().anchor_exists(name)
+}
+
+/// The ID of the anchor named `name`, registered if it doesn't exist yet,
+/// see [`Profiler::get_anchor_id`]. While this thread holds the profiler
+/// through [`lock`], returns the root anchor's ID `0` instead, whose time and
+/// bytes never show up in reports.
+pub fn anchor_id(name: &str) -> usize {
+    if lock_held() {
+        return 0;
+    }
+    lock_profiler().get_anchor_id(name)
+}
+
+/// Like [`anchor_id`], see [`Profiler::get_static_anchor_id`].
+pub fn static_anchor_id(name: &'static str) -> usize {
+    if lock_held() {
+        return 0;
+    }
+    lock_profiler().get_static_anchor_id(name)
+}
+
+/// Sets a custom metric on an anchor, see
+/// [`Profiler::add_custom_metric_by_id`]. Does nothing while this thread
+/// holds the profiler through [`lock`].
+pub fn add_custom_metric_by_id(anchor_id: usize, key: &str, value: f64) {
+    if lock_held() {
+        return;
+    }
+    lock_profiler().add_custom_metric_by_id(anchor_id, key, value);
+}
+
+pub fn set_sample_rate(n: usize) {
+    Profiler::set_global_sample_rate(n);
+}
+
+/// Panics unless the anchor named `anchor_name` completed exactly
+/// `expected_calls` blocks.
+#[track_caller]
+pub fn assert_called(anchor_name: &str, expected_calls: usize) {
+    let result = lock_profiler().assert_called(anchor_name, expected_calls);
+    if let Err(e) = result {
+        panic!("{}", e);
+    }
+}
+
+pub fn alias(original: &str, alias: &str) {
+    lock_profiler().set_anchor_alias(original, alias);
+}
+
+/// Replaces the report header line, see [`Profiler::set_header`].
+pub fn set_header(header: &str) {
+    lock_profiler().set_header(header);
+}
+
+/// Ends reports with a footer line, see [`Profiler::set_footer`].
+pub fn set_footer(footer: &str) {
+    lock_profiler().set_footer(footer);
+}
+
+pub fn set_precision(decimal_places: usize) {
+    lock_profiler().set_output_precision(decimal_places);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs blocks on `p` itself rather than on the global profiler, so tests
+    // don't share state.
+    pub(crate) fn start(p: &mut Profiler, id: usize) -> Block {
+        p.begin_block(id)
+    }
+
+    pub(crate) fn end(p: &mut Profiler, mut block: Block) {
+        // Skipped blocks never touch the profiler.
+        if !block.recorded {
+            return;
+        }
+        let ended = block.ended();
+        block.recorded = false;
+        p.end_block(ended);
+    }
+
+    // Runs one call of each anchor in `ids`, each nested in the one before.
+    pub(crate) fn call_chain(p: &mut Profiler, ids: &[usize]) {
+        if let Some((&id, callees)) = ids.split_first() {
+            let block = start(p, id);
+            spin(Duration::from_micros(50));
+            call_chain(p, callees);
+            end(p, block);
+        }
+    }
+
+    pub(crate) fn spin(duration: Duration) {
+        let start = Instant::now();
+        while start.elapsed() < duration {}
+    }
+
+    // Runs the test `name` alone in a child process, where `in_child()` is
+    // true, and returns what it printed to stdout and stderr.
+    #[cfg(unix)]
+    fn run_in_child(name: &str) -> (String, String) {
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args([name, "--exact", "--nocapture"])
+            .env("PPROF_TEST_CHILD", "1")
+            .output()
+            .unwrap();
+        (String::from_utf8_lossy(&output.stdout).into_owned(), String::from_utf8_lossy(&output.stderr).into_owned())
+    }
+
+    #[cfg(unix)]
+    fn in_child() -> bool {
+        std::env::var_os("PPROF_TEST_CHILD").is_some()
+    }
+
+    // A profiler whose only anchor has all the self time, so it is colored.
+    fn hot_profiler() -> Profiler {
+        let mut p = Profiler::new();
+        let id = p.get_anchor_id("hot");
+        p.record_call(id, Duration::from_millis(5));
+        p
+    }
+
+    // A profiler whose total time is exactly `window`.
+    fn profiler_spanning(window: Duration) -> Profiler {
+        let mut p = Profiler::new();
+        p.end = Some(p.start + window);
+        p
+    }
+
+    fn plain_report(p: &mut Profiler) -> String {
+        p.set_color(false);
+        let mut report = String::new();
+        p.write_report(&mut report).unwrap();
+        report
+    }
+
+    fn result_of(p: &Profiler, name: &str) -> AnchorResult {
+        p.results().anchors.into_iter().find(|a| a.name == name).unwrap()
+    }
+
+    // Runs one call of `id` that spends `self_time` in itself and then runs
+    // `callees` inside it. The call is recorded as taking exactly that plus
+    // what its callees took, so being preempted while spinning can't change
+    // the proportions tests check.
+    fn timed(p: &mut Profiler, id: usize, self_time: Duration, callees: impl FnOnce(&mut Profiler)) {
+        let mut block = start(p, id);
+        spin(self_time);
+        callees(p);
+        // Every callee's time has been taken out of this anchor's self time.
+        let callees_ticks = block.old_elapsed_exclusive.wrapping_sub(p.anchors[id].elapsed_exclusive);
+        let mut ended = block.ended();
+        ended.elapsed = (self_time.as_secs_f64() * p.freq()).round() as u64 + callees_ticks;
+        block.recorded = false;
+        p.end_block(ended);
+    }
+
+    // Serializes the tests that use the global profiler, which starts out
+    // reset and recording every block for each of them.
+    fn global() -> MutexGuard<'static, ()> {
+        static GLOBAL: Mutex<()> = Mutex::new(());
+        let guard = GLOBAL.lock().unwrap_or_else(|e| e.into_inner());
+        // So one failing test doesn't fail all the others.
+        PROFILER.clear_poison();
+        *lock() = Profiler::global();
+        set_sample_rate(1);
+        guard
+    }
+
+    #[test]
+    fn warmup_call_hands_children_to_enclosing_block() {
+        let mut p = Profiler::new();
+        let inner = p.get_anchor_id("inner");
+        // Only anchors created from now on warm up.
+        p.set_warmup_calls(1);
+        let outer = p.get_anchor_id("outer");
+        for _ in 0..2 {
+            let outer_block = start(&mut p, outer);
+            spin(Duration::from_micros(200));
+            let inner_block = start(&mut p, inner);
+            spin(Duration::from_micros(500));
+            end(&mut p, inner_block);
+            end(&mut p, outer_block);
+        }
+        let (outer, inner) = (&p.anchors[outer], &p.anchors[inner]);
+        assert_eq!(outer.calls, 1);
+        assert_eq!(inner.calls, 2);
+        // Only the second inner call ran inside a recorded outer call.
+        assert!(outer.elapsed_exclusive <= outer.elapsed_inclusive);
+        assert!(outer.elapsed_inclusive - outer.elapsed_exclusive < inner.elapsed_inclusive);
+        // The first inner call came out of the root's share instead.
+        let first_inner = inner.elapsed_inclusive - (outer.elapsed_inclusive - outer.elapsed_exclusive);
+        assert_eq!(p.anchors[0].elapsed_exclusive, 0u64.wrapping_sub(first_inner + outer.elapsed_inclusive));
+    }
+
+    #[test]
+    fn time_evaluates_to_its_body() {
+        let _global = global();
+        assert_eq!(time!("sum" => { 2 + 3 }), 5);
+    }
+
+    #[test]
+    fn time_takes_names_ending_in_a_brace() {
+        let _global = global();
+        // Only used when the `profile` feature is enabled.
+        #[allow(unused_variables)]
+        let short = true;
+        {
+            let _guard = time!(if short { "short" } else { "long" });
+        }
+        #[cfg(feature = "profile")]
+        assert!(lock().anchor_exists("pprof::tests::time_takes_names_ending_in_a_brace[short]"));
+    }
+
+    #[test]
+    fn macros_skip_blocks_while_the_lock_is_held() {
+        let _global = global();
+        let guard = lock();
+        {
+            let _plain = block!();
+            let _literal = block!("literal");
+            let name = String::from("expr");
+            let _expr = block!(name);
+            let _child = block!("child", parent: 0);
+            let _bytes = block!("bytes", 10);
+            let _site = block_site!();
+            let _named = block_named!("named");
+            assert_eq!(block_id!(), 0);
+            assert_eq!(block_id!("id"), 0);
+            block_metric!(0, "key", 1.0);
+            with("with", || ());
+            scope_bytes("scope", 10, || ());
+        }
+        drop(guard);
+        assert_eq!(anchor_count(), 0);
+    }
+
+    #[test]
+    fn block_ending_while_the_lock_is_held_is_recorded_on_release() {
+        let _global = global();
+        let block = block_named!("late");
+        let guard = lock();
+        drop(block);
+        assert!(guard.assert_called("late", 0).is_ok());
+        drop(guard);
+        assert_called("late", 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn save_on_exit_child_exits() {
+        if !in_child() {
+            return;
+        }
+        save_on_exit(None);
+        drop(block_named!("before_exit"));
+        std::process::exit(0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn save_on_exit_prints_on_process_exit() {
+        let (stdout, _) = run_in_child("tests::save_on_exit_child_exits");
+        assert_eq!(stdout.matches("--- PProf Results").count(), 1);
+        assert!(stdout.contains("before_exit[1]"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn save_on_exit_child_survives_panics() {
+        if !in_child() {
+            return;
+        }
+        save_on_exit(None);
+        let _ = std::panic::catch_unwind(|| panic!("caught"));
+        let _ = thread::spawn(|| panic!("worker")).join();
+        drop(block_named!("after_panics"));
+        std::process::exit(0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn save_on_exit_ignores_panics_that_do_not_end_the_process() {
+        let (stdout, _) = run_in_child("tests::save_on_exit_child_survives_panics");
+        assert_eq!(stdout.matches("--- PProf Results").count(), 1);
+        assert!(stdout.contains("after_panics[1]"));
+    }
+
+    #[cfg(feature = "rdtsc")]
+    #[test]
+    fn switching_clocks_discards_the_statistics() {
+        let mut p = hot_profiler();
+        p.set_wall_clock_mode(true);
+        assert!(p.is_wall_clock_mode());
+        assert_eq!(p.results().anchors[0].calls, 0);
+    }
+
+    #[cfg(feature = "rdtsc")]
+    #[test]
+    fn switching_clocks_is_refused_while_blocks_are_open() {
+        let mut p = hot_profiler();
+        let block = start(&mut p, 1);
+        p.set_wall_clock_mode(true);
+        end(&mut p, block);
+        assert!(!p.is_wall_clock_mode());
+        assert_eq!(p.results().anchors[0].calls, 2);
+    }
+
+    #[test]
+    fn trimming_removes_anchors_without_calls() {
+        let mut p = hot_profiler();
+        p.get_anchor_id("idle");
+        p.trim_inactive_anchors();
+        let names: Vec<_> = p.results().anchors.into_iter().map(|a| a.name).collect();
+        assert_eq!(names, ["hot"]);
+    }
+
+    #[test]
+    fn trimming_is_refused_while_blocks_are_open() {
+        let mut p = Profiler::new();
+        let idle = p.get_anchor_id("idle");
+        let open = p.get_anchor_id("open");
+        let block = start(&mut p, open);
+        p.trim_inactive_anchors();
+        end(&mut p, block);
+        assert_eq!(p.get_anchor_id("idle"), idle);
+        assert_eq!(p.anchor_at_index(open).unwrap().name, "open");
+        assert_eq!(p.anchor_at_index(open).unwrap().calls, 1);
+    }
+
+    #[cfg(feature = "counting-allocator")]
+    #[test]
+    fn blocks_count_their_allocations() {
+        let mut p = Profiler::new();
+        let id = p.get_anchor_id("alloc");
+        let block = start(&mut p, id);
+        let v = std::hint::black_box(vec![0u8; 4096]);
+        drop(v);
+        end(&mut p, block);
+        p.set_color(false);
+        let mut report = String::new();
+        p.write_report(&mut report).unwrap();
+        assert!(report.contains("allocs=1 "), "{}", report);
+    }
+
+    #[cfg(feature = "counting-allocator")]
+    #[test]
+    fn blocks_can_end_on_a_fresh_thread() {
+        let mut p = Profiler::new();
+        let id = p.get_anchor_id("moved");
+        let v = std::hint::black_box(vec![0u8; 4096]);
+        drop(v);
+        let block = start(&mut p, id);
+        let block = thread::spawn(move || {
+            let mut block = block;
+            let ended = block.ended();
+            block.recorded = false;
+            ended
+        })
+        .join()
+        .unwrap();
+        p.end_block(block);
+        assert_eq!(p.anchor_at_index(id).unwrap().calls, 1);
+    }
+
+    #[test]
+    fn open_blocks_are_reported_as_leaked() {
+        let mut p = Profiler::new();
+        let id = p.get_anchor_id("open");
+        let block = start(&mut p, id);
+        assert_eq!(p.assert_no_leaked_blocks(), Err(LeakedBlockError { anchor_name: "open".to_string() }));
+        end(&mut p, block);
+        assert_eq!(p.assert_no_leaked_blocks(), Ok(()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn open_blocks_child() {
+        if !in_child() {
+            return;
+        }
+        let mut p = Profiler::new();
+        let id = p.get_anchor_id("open");
+        let block = start(&mut p, id);
+        p.print();
+        end(&mut p, block);
+    }
+
+    #[cfg(all(unix, debug_assertions))]
+    #[test]
+    fn printing_with_an_open_block_warns_in_debug_builds() {
+        let (stdout, stderr) = run_in_child("tests::open_blocks_child");
+        assert!(stdout.contains("test result: ok. 1 passed"), "{}", stdout);
+        assert!(stderr.contains("pprof warning: printing an incomplete profile, block of open is still open"), "{}", stderr);
+    }
+
+    #[test]
+    fn disabled_color_leaves_reports_plain() {
+        let mut p = hot_profiler();
+        p.set_color(false);
+        let mut report = String::new();
+        p.write_report(&mut report).unwrap();
+        assert!(!report.contains('\x1b'));
+    }
+
+    #[test]
+    fn colors_are_dropped_for_streams_that_are_not_terminals() {
+        let mut p = hot_profiler();
+        p.set_output_target(OutputTarget::Stderr);
+        p.color = None;
+        let colored = format!("{}hot{}", ANSI_RED, ANSI_RESET);
+        // Reports are only colored to begin with if the real stderr is a
+        // terminal.
+        if p.use_color() {
+            assert_eq!(p.uncolored_unless(&colored, false), "hot");
+            assert_eq!(p.uncolored_unless(&colored, true), colored);
+        }
+        p.set_color(true);
+        assert_eq!(p.uncolored_unless(&colored, false), colored);
+    }
+
+    #[cfg(feature = "log")]
+    #[test]
+    fn log_target_is_never_colored() {
+        let mut p = hot_profiler();
+        p.set_color(true);
+        p.set_output_target(OutputTarget::Log(log::Level::Info));
+        let mut report = String::new();
+        p.write_report(&mut report).unwrap();
+        assert!(!report.contains('\x1b'));
+    }
+
+    #[test]
+    fn a_block_covering_the_whole_profile_is_fully_instrumented() {
+        let mut p = profiler_spanning(Duration::from_millis(10));
+        let id = p.get_anchor_id("all");
+        p.record_call(id, Duration::from_millis(10));
+        assert!(plain_report(&mut p).contains("Instrumented: 100.0% "));
+    }
+
+    #[test]
+    fn with_capacity_registers_that_many_anchors_without_reallocating() {
+        let mut p = Profiler::with_capacity(5);
+        for i in 0..5 {
+            p.get_anchor_id(&format!("anchor{}", i));
+        }
+        assert_eq!(p.anchors.capacity(), 6);
+    }
+
+    #[test]
+    fn long_names_are_truncated_to_the_maximum_length() {
+        let mut p = Profiler::new();
+        let id = p.get_anchor_id(&"a".repeat(100));
+        p.record_call(id, Duration::from_millis(1));
+        p.set_max_name_length(40);
+        let report = plain_report(&mut p);
+        assert!(report.contains(&format!(" {}…[1]", "a".repeat(39))), "{}", report);
+        assert!(!report.contains(&"a".repeat(40)));
+    }
+
+    #[test]
+    fn a_block_in_a_loop_adds_every_iteration_to_one_anchor() {
+        let _global = global();
+        for _ in 0..1000 {
+            let _b = block_named!("loop");
+            spin(Duration::from_micros(10));
+        }
+        let anchor = result_of(&lock(), "loop");
+        assert_eq!(anchor.calls, 1000);
+        assert!(anchor.inclusive_ms >= 10.0, "{}", anchor.inclusive_ms);
+        assert!(anchor.inclusive_ms < 100.0, "{}", anchor.inclusive_ms);
+    }
+
+    #[test]
+    fn output_precision_sets_the_decimal_places() {
+        let mut p = Profiler::new();
+        let id = p.get_anchor_id("pi");
+        p.record_call(id, Duration::from_nanos(3_141_593));
+        p.set_output_precision(2);
+        let report = plain_report(&mut p);
+        assert!(report.contains("3.14ms"), "{}", report);
+        assert!(!report.contains("3.1416ms"), "{}", report);
+    }
+
+    #[test]
+    fn results_leave_out_the_root_sentinel() {
+        let mut p = hot_profiler();
+        let ids = [p.get_anchor_id("outer"), p.get_anchor_id("inner")];
+        call_chain(&mut p, &ids);
+        let results = p.results();
+        assert_eq!(results.anchors.len(), 3);
+        assert!(results.anchors.iter().all(|a| !a.name.is_empty()));
+    }
+
+    #[test]
+    fn a_sample_rate_of_n_records_every_nth_block() {
+        let _global = global();
+        set_sample_rate(10);
+        for _ in 0..100 {
+            let _b = block_named!("sampled");
+        }
+        set_sample_rate(1);
+        assert_called("sampled", 10);
+    }
+
+    #[test]
+    fn blocks_in_closures_are_named_without_compiler_suffixes() {
+        let _global = global();
+        let f = || {
+            let _b = block!();
+        };
+        f();
+        let names: Vec<_> = lock().results().anchors.into_iter().map(|a| a.name).collect();
+        assert_eq!(names, ["pprof::tests::blocks_in_closures_are_named_without_compiler_suffixes[closure]"]);
+    }
+
+    #[test]
+    fn total_exclusive_time_of_a_block_covering_the_profile_is_its_duration() {
+        let mut p = profiler_spanning(Duration::from_millis(10));
+        let id = p.get_anchor_id("all");
+        p.record_call(id, Duration::from_millis(10));
+        assert!((p.total_exclusive_ms() - p.elapsed().as_secs_f64() * 1000.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn total_inclusive_time_counts_every_nesting_level() {
+        let mut p = Profiler::new();
+        let ids = [p.get_anchor_id("outer"), p.get_anchor_id("inner")];
+        call_chain(&mut p, &ids);
+        let (outer, inner) = (result_of(&p, "outer"), result_of(&p, "inner"));
+        assert!((p.total_inclusive_ms() - (outer.inclusive_ms + inner.inclusive_ms)).abs() < 1e-9);
+        assert!((p.total_exclusive_ms() - outer.inclusive_ms).abs() < 1e-9);
+    }
+
+    #[test]
+    fn max_depth_is_the_deepest_nesting() {
+        let mut p = Profiler::new();
+        let ids = [p.get_anchor_id("a"), p.get_anchor_id("b"), p.get_anchor_id("c")];
+        call_chain(&mut p, &ids);
+        call_chain(&mut p, &ids[..1]);
+        assert_eq!(p.max_depth(), 3);
+    }
+
+    #[test]
+    fn a_block_covering_half_the_profile_is_half_the_coverage() {
+        let mut p = profiler_spanning(Duration::from_millis(20));
+        let id = p.get_anchor_id("half");
+        p.record_call(id, Duration::from_millis(10));
+        assert!((p.wall_clock_coverage() - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn anchor_counts_leave_out_the_root_sentinel() {
+        let mut p = hot_profiler();
+        p.get_anchor_id("idle");
+        assert_eq!(p.anchor_count(), 2);
+        assert_eq!(p.active_anchor_count(), 1);
+    }
+
+    #[test]
+    fn recursive_anchors_are_flagged() {
+        let mut p = Profiler::new();
+        let factorial = p.get_anchor_id("factorial");
+        let leaf = p.get_anchor_id("leaf");
+        call_chain(&mut p, &[factorial, factorial, factorial, leaf]);
+        let report = plain_report(&mut p);
+        let line = |name: &str| report.lines().find(|l| l.contains(&format!(" {}[", name))).unwrap().to_string();
+        assert!(line("factorial").contains("[REC]"), "{}", report);
+        assert!(!line("leaf").contains("[REC]"), "{}", report);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn auto_print_child_runs_25_blocks() {
+        if !in_child() {
+            return;
+        }
+        let mut p = Profiler::new();
+        let id = p.get_anchor_id("tick");
+        p.set_auto_print_interval(10);
+        for _ in 0..25 {
+            call_chain(&mut p, &[id]);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn auto_print_prints_after_every_n_blocks() {
+        let (_, stderr) = run_in_child("tests::auto_print_child_runs_25_blocks");
+        assert_eq!(stderr.matches("--- PProf Results").count(), 2, "{}", stderr);
+        assert!(stderr.contains("tick[10]") && stderr.contains("tick[20]"), "{}", stderr);
+    }
+
+    #[test]
+    fn warmup_calls_are_left_out_of_the_statistics() {
+        let mut p = Profiler::new();
+        p.set_warmup_calls(5);
+        let id = p.get_anchor_id("warm");
+        for i in 0..10 {
+            let block = start(&mut p, id);
+            spin(Duration::from_micros(if i < 5 { 2000 } else { 10 }));
+            end(&mut p, block);
+        }
+        let warm = result_of(&p, "warm");
+        assert_eq!(warm.calls, 5);
+        assert!(warm.inclusive_ms < 5.0, "{}", warm.inclusive_ms);
+    }
+
+    #[cfg(feature = "percentiles")]
+    #[test]
+    fn histogram_p50_is_the_median_call() {
+        let mut p = Profiler::new();
+        let id = p.get_anchor_id("normal");
+        // Sums of twelve uniform draws are close to normally distributed,
+        // here around 2ms.
+        let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+        let mut uniform = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            (seed >> 11) as f64 / (1u64 << 53) as f64
+        };
+        let mut durations: Vec<Duration> = (0..100)
+            .map(|_| {
+                let normal: f64 = (0..12).map(|_| uniform()).sum::<f64>() - 6.0;
+                Duration::from_secs_f64((2.0 + 0.5 * normal) / 1000.0)
+            })
+            .collect();
+        for &d in &durations {
+            p.record_call(id, d);
+        }
+        let freq = p.freq();
+        p.anchors[id].durations = durations.iter().map(|d| (d.as_secs_f64() * freq) as u64).collect();
+
+        durations.sort();
+        let median_ms = (durations[49] + durations[50]).as_secs_f64() / 2.0 * 1000.0;
+        let report = plain_report(&mut p);
+        let p50: f64 = report.split("p50=").nth(1).unwrap().split("ms").next().unwrap().parse().unwrap();
+        assert!((p50 - median_ms).abs() < median_ms * 0.1, "{} vs {}", p50, median_ms);
+    }
+
+    #[test]
+    fn waiting_for_the_lock_counts_as_contention() {
+        let _global = global();
+        let before = Profiler::lock_contention_count();
+        let guard = lock();
+        let waiter = thread::spawn(|| drop(block_named!("contended")));
+        thread::sleep(Duration::from_millis(50));
+        drop(guard);
+        waiter.join().unwrap();
+        assert!(Profiler::lock_contention_count() > before);
+        assert_called("contended", 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn print_normalized_child() {
+        if !in_child() {
+            return;
+        }
+        let mut p = Profiler::new();
+        let (a, b) = (p.get_anchor_id("a"), p.get_anchor_id("b"));
+        for _ in 0..4 {
+            p.record_call(a, Duration::from_millis(2));
+        }
+        for _ in 0..2 {
+            p.record_call(b, Duration::from_micros(500));
+        }
+        p.set_color(false);
+        p.print_normalized();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn print_normalized_shows_averages_per_call() {
+        let (stdout, _) = run_in_child("tests::print_normalized_child");
+        assert!(stdout.contains("a[4] - avg_self=2.0000ms avg_total=2.0000ms"), "{}", stdout);
+        assert!(stdout.contains("b[2] - avg_self=0.5000ms avg_total=0.5000ms"), "{}", stdout);
+    }
+
+    #[test]
+    fn display_writes_the_report() {
+        let _global = global();
+        drop(block_named!("shown"));
+        let mut p = lock();
+        p.set_color(false);
+        let report = format!("{}", *p);
+        assert!(report.starts_with("--- PProf Results"), "{}", report);
+        assert!(report.contains(" shown[1]"), "{}", report);
+    }
+
+    #[test]
+    fn aliases_replace_anchor_names_in_reports() {
+        let mut p = Profiler::new();
+        let id = p.get_anchor_id("my_crate::parser::parse_document");
+        p.record_call(id, Duration::from_millis(1));
+        p.set_anchor_alias("my_crate::parser::parse_document", "parse");
+        let report = plain_report(&mut p);
+        assert!(report.contains(" parse[1]"), "{}", report);
+        assert!(!report.contains("parse_document"), "{}", report);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn log_prefix_child() {
+        if !in_child() {
+            return;
+        }
+        let mut p = hot_profiler();
+        p.set_log_prefix("[PPROF] ");
+        p.print();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn log_prefix_starts_every_printed_line() {
+        let (stdout, _) = run_in_child("tests::log_prefix_child");
+        let start = stdout.find("[PPROF] --- PProf Results").unwrap();
+        let prefixed = stdout[start..].lines().take_while(|l| l.starts_with("[PPROF] ")).count();
+        assert_eq!(prefixed, plain_report(&mut hot_profiler()).lines().count(), "{}", stdout);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn top_n_with_callers_child() {
+        if !in_child() {
+            return;
+        }
+        let mut p = Profiler::new();
+        let leaf = p.get_anchor_id("leaf");
+        for (i, caller) in ["a", "b", "c", "d"].into_iter().enumerate() {
+            let caller = p.get_anchor_id(caller);
+            // Twice the calls of the caller before, so timing noise can't
+            // reorder them.
+            for _ in 0..1 << i {
+                call_chain(&mut p, &[caller, leaf]);
+            }
+        }
+        p.set_color(false);
+        p.print_top_n_with_callers(1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn caller_shares_add_up_to_the_whole() {
+        let (stdout, _) = run_in_child("tests::top_n_with_callers_child");
+        let line = stdout.lines().find(|l| l.contains("leaf [15]")).unwrap();
+        let callers = line.split(" <- called from: ").nth(1).unwrap();
+        assert_eq!(callers.matches(", ").count(), 3, "{}", line);
+        assert!(callers.starts_with("d (") && callers.contains("other ("), "{}", line);
+        let total: f64 = callers.split(", ").map(|c| c.split('(').nth(1).unwrap().trim_end_matches("%)").parse::<f64>().unwrap()).sum();
+        assert!((total - 100.0).abs() < 0.01, "{}", line);
+    }
+
+    #[test]
+    fn critical_path_follows_the_slowest_callees() {
+        let mut p = Profiler::new();
+        let [a, b, c, d, e, f] = ["a", "b", "c", "d", "e", "f"].map(|name| p.get_anchor_id(name));
+        let us = Duration::from_micros;
+        timed(&mut p, a, us(50), |p| {
+            timed(p, c, us(50), |p| {
+                timed(p, e, us(1000), |_| {});
+                timed(p, f, us(100), |_| {});
+            });
+            timed(p, d, us(300), |_| {});
+        });
+        timed(&mut p, b, us(500), |_| {});
+        let path: Vec<_> = p.compute_critical_path().into_iter().map(|a| a.name).collect();
+        assert_eq!(path, ["a", "c", "e"]);
+    }
+
+    #[test]
+    fn blocks_are_not_recorded_while_disabled() {
+        let _global = global();
+        lock().set_enabled(false);
+        drop(block_named!("toggled"));
+        assert!(lock().results().anchors.iter().all(|a| a.calls == 0));
+        lock().set_enabled(true);
+        drop(block_named!("toggled"));
+        assert_called("toggled", 1);
+    }
+
+    #[test]
+    fn blocks_record_normally_after_trimming() {
+        let mut p = Profiler::new();
+        p.get_anchor_id("idle");
+        let ids = [p.get_anchor_id("outer"), p.get_anchor_id("inner")];
+        call_chain(&mut p, &ids);
+        p.trim_inactive_anchors();
+        assert_eq!(p.anchors.len(), 3);
+        let ids = [p.get_anchor_id("outer"), p.get_anchor_id("inner")];
+        call_chain(&mut p, &ids);
+        assert_eq!(result_of(&p, "outer").calls, 2);
+        assert_eq!(result_of(&p, "inner").calls, 2);
+        assert!(p.assert_no_leaked_blocks().is_ok());
+    }
+
+    #[test]
+    fn assert_called_checks_the_exact_call_count() {
+        let _global = global();
+        for _ in 0..5 {
+            let _b = block_named!("parse_json");
+        }
+        assert_called("parse_json", 5);
+        let error = lock().assert_called("parse_json", 4).unwrap_err();
+        assert_eq!(error, AssertionError { anchor_name: "parse_json".to_string(), expected_calls: 4, actual_calls: 5 });
+        assert!(lock().assert_called("never", 0).is_ok());
+    }
+
+    #[test]
+    fn passthrough_anchors_can_be_hidden() {
+        let mut p = profiler_spanning(Duration::from_millis(100));
+        let [wrapper, work] = ["wrapper", "work"].map(|name| p.get_anchor_id(name));
+        timed(&mut p, wrapper, Duration::ZERO, |p| timed(p, work, Duration::from_millis(2), |_| {}));
+        assert!(plain_report(&mut p).contains(" wrapper[1]"));
+        p.set_hide_passthrough(true);
+        let report = plain_report(&mut p);
+        assert!(!report.contains(" wrapper[1]"), "{}", report);
+        assert!(report.contains(" work[1]"), "{}", report);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn periodic_reporter_child() {
+        if !in_child() {
+            return;
+        }
+        drop(block_named!("periodic"));
+        let reporter = start_periodic_reporter(50);
+        thread::sleep(Duration::from_millis(100));
+        reporter.stop();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn periodic_reporter_prints_within_twice_the_interval() {
+        let (_, stderr) = run_in_child("tests::periodic_reporter_child");
+        assert!(stderr.contains("--- PProf Results"), "{}", stderr);
+        assert!(stderr.contains(" periodic[1]"), "{}", stderr);
+    }
+
+    #[test]
+    fn anchors_over_budget_are_violations() {
+        let mut p = Profiler::new();
+        for (name, ms) in [("fast", 1), ("slow", 5), ("slower", 8)] {
+            let id = p.get_anchor_id(name);
+            p.record_call(id, Duration::from_millis(ms));
+        }
+        let budget: HashMap<String, f64> = [("fast", 2.0), ("slow", 4.0), ("slower", 4.0), ("never", 1.0)]
+            .into_iter()
+            .map(|(name, ms)| (name.to_string(), ms))
+            .collect();
+        let violations = p.report_timing_budget(&budget);
+        let names: Vec<_> = violations.iter().map(|v| v.name.as_str()).collect();
+        assert_eq!(names, ["slow", "slower"]);
+        assert_eq!(violations[0].budget_ms, 4.0);
+        assert!((violations[0].actual_ms - 5.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn escape_sequences_in_names_are_stripped_from_reports() {
+        let mut p = Profiler::new();
+        let id = p.get_anchor_id("user\x1b[31m input\nline");
+        p.record_call(id, Duration::from_millis(1));
+        let report = plain_report(&mut p);
+        assert!(!report.contains('\x1b'), "{:?}", report);
+        assert!(report.contains(" user input\\nline[1]"), "{}", report);
+    }
+
+    #[test]
+    fn every_block_site_gets_its_own_anchor() {
+        let _global = global();
+        for _ in 0..2 {
+            drop(block_site!("parse"));
+            drop(block_site!("parse"));
+        }
+        let results = lock().results();
+        assert_eq!(results.anchors.len(), 2);
+        assert_ne!(results.anchors[0].name, results.anchors[1].name);
+        assert!(results.anchors.iter().all(|a| a.calls == 2 && a.name.contains("[parse]@src/lib.rs:")));
+    }
+
+    #[test]
+    fn percentages_of_an_empty_profile_are_not_available() {
+        let mut p = profiler_spanning(Duration::ZERO);
+        let id = p.get_anchor_id("instant");
+        p.record_call(id, Duration::from_millis(1));
+        let report = plain_report(&mut p);
+        assert!(!report.contains("NaN") && !report.contains("inf"), "{}", report);
+        assert!(report.contains("(N/A)"), "{}", report);
+    }
+
+    #[test]
+    fn bars_are_proportional_to_self_time() {
+        let mut p = Profiler::new();
+        for (name, ms) in [("long", 4), ("short", 2)] {
+            let id = p.get_anchor_id(name);
+            p.record_call(id, Duration::from_millis(ms));
+        }
+        let report = plain_report(&mut p);
+        let filled = |name: &str| {
+            let line = report.lines().find(|l| l.contains(&format!(" {}[", name))).unwrap();
+            line.matches('█').count()
+        };
+        assert_eq!(filled("long"), 20);
+        assert_eq!(filled("short"), 10);
+    }
+
+    #[test]
+    fn comments_are_printed_below_their_anchor() {
+        let mut p = hot_profiler();
+        p.set_anchor_comment("hot", "disk I/O, expected");
+        let report = plain_report(&mut p);
+        let mut lines = report.lines().skip_while(|l| !l.contains(" hot[1]"));
+        assert!(lines.next().is_some());
+        assert!(lines.next().unwrap().contains("disk I/O, expected"), "{}", report);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn print_if_slow_child() {
+        if !in_child() {
+            return;
+        }
+        let mut p = hot_profiler();
+        assert!(!p.print_if_slow(10.0));
+        assert!(p.print_if_slow(1.0));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn print_if_slow_prints_only_slow_profiles() {
+        let (stdout, _) = run_in_child("tests::print_if_slow_child");
+        assert!(stdout.contains("test result: ok. 1 passed"), "{}", stdout);
+        assert_eq!(stdout.matches("--- PProf Results").count(), 1, "{}", stdout);
+    }
+
+    #[cfg(feature = "timeline")]
+    #[test]
+    fn timeline_spans_from_the_first_start_to_the_last_end() {
+        let mut p = Profiler::new();
+        let id = p.get_anchor_id("twice");
+        call_chain(&mut p, &[id]);
+        spin(Duration::from_micros(200));
+        call_chain(&mut p, &[id]);
+        let twice = result_of(&p, "twice");
+        assert!(twice.first_start_ns < twice.last_end_ns);
+        assert!(twice.last_end_ns - twice.first_start_ns >= 300_000, "{:?}", twice);
+    }
+
+    #[test]
+    fn hot_path_ratio_is_the_hottest_anchors_share() {
+        let mut p = Profiler::new();
+        assert_eq!(p.hot_path_ratio(), 0.0);
+        for (name, ms) in [("cold", 2), ("hot", 8)] {
+            let id = p.get_anchor_id(name);
+            p.record_call(id, Duration::from_millis(ms));
+        }
+        assert!((p.hot_path_ratio() - 0.8).abs() < 1e-6);
+        assert_eq!(p.hot_path_name(), Some("hot"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn concurrent_prints_child() {
+        if !in_child() {
+            return;
+        }
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                thread::spawn(move || {
+                    let mut p = hot_profiler();
+                    p.set_log_prefix(&format!("[t{}] ", i));
+                    for _ in 0..20 {
+                        p.print();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn concurrent_prints_do_not_interleave() {
+        let (stdout, _) = run_in_child("tests::concurrent_prints_child");
+        let report_lines = plain_report(&mut hot_profiler()).lines().count();
+        let lines: Vec<&str> = stdout.lines().collect();
+        let mut reports = 0;
+        for (i, line) in lines.iter().enumerate() {
+            if let Some(at) = line.find("] --- PProf Results") {
+                let prefix = &line[at - 3..at + 2];
+                assert!(lines[i + 1..i + report_lines].iter().all(|l| l.starts_with(prefix)), "{}", stdout);
+                reports += 1;
+            }
+        }
+        assert_eq!(reports, 80);
+    }
+
+    #[test]
+    fn anchor_at_index_reads_an_anchor_by_id() {
+        let mut p = Profiler::new();
+        let id = p.get_anchor_id("by_id");
+        call_chain(&mut p, &[id]);
+        assert_eq!(p.anchor_at_index(id), Some(result_of(&p, "by_id")));
+        assert_eq!(p.anchor_at_index(0), None);
+        assert_eq!(p.anchor_at_index(id + 1), None);
+    }
+
+    #[test]
+    fn scope_bytes_reports_the_throughput() {
+        let _global = global();
+        let result = scope_bytes("transfer", 1 << 30, || {
+            spin(Duration::from_millis(1));
+            7
+        });
+        assert_eq!(result, 7);
+        let mut p = lock();
+        assert_eq!(result_of(&p, "transfer").bytes, 1 << 30);
+        let report = plain_report(&mut p);
+        let line = report.lines().find(|l| l.contains(" transfer[1]")).unwrap();
+        assert!(line.contains("throughput=1.00 GB at "), "{}", line);
+        let gb_s: f64 = line.split(" at ").nth(1).unwrap().split(" GB/s").next().unwrap().parse().unwrap();
+        // 1 GB in at least 1ms.
+        assert!(gb_s > 0.0 && gb_s < 1100.0, "{}", line);
+    }
+
+    #[test]
+    fn estimated_overhead_is_below_ten_microseconds_per_block() {
+        let overhead = Profiler::estimate_overhead_ns();
+        assert!(overhead > 0 && overhead < 10_000, "{}ns", overhead);
+    }
+
+    #[test]
+    fn anchors_past_the_limit_go_to_the_root() {
+        let mut p = Profiler::new();
+        p.set_max_anchors(2);
+        let [a, b] = ["a", "b"].map(|name| p.get_anchor_id(name));
+        assert_eq!((a, b), (1, 2));
+        assert_eq!(p.get_anchor_id("c"), 0);
+        assert_eq!(p.get_anchor_id("a"), a);
+        assert_eq!(p.overflow_count(), 1);
+        assert_eq!(p.anchor_count(), 2);
+    }
+
+    #[test]
+    fn overflowed_blocks_count_as_the_enclosing_block() {
+        let mut p = Profiler::new();
+        p.set_max_anchors(1);
+        let outer = p.get_anchor_id("outer");
+        let outer_block = start(&mut p, outer);
+        let overflowed = p.get_anchor_id("overflowed");
+        let block = start(&mut p, overflowed);
+        assert_eq!(p.assert_no_leaked_blocks(), Err(LeakedBlockError { anchor_name: "outer".to_string() }));
+        spin(Duration::from_micros(200));
+        end(&mut p, block);
+        end(&mut p, outer_block);
+        let outer = result_of(&p, "outer");
+        assert_eq!(outer.calls, 1);
+        assert_eq!(outer.ticks_exclusive, outer.ticks_inclusive);
+        assert_eq!(p.instrumented_ticks(), outer.ticks_inclusive);
+        assert_eq!(p.max_depth(), 1);
+    }
+
+    #[test]
+    fn percentages_follow_the_percentage_base() {
+        let mut p = profiler_spanning(Duration::from_millis(10));
+        let id = p.get_anchor_id("half");
+        p.record_call(id, Duration::from_millis(5));
+        assert!(plain_report(&mut p).contains("total=5.0000ms (50.0000%)"));
+        p.set_percentage_base(PercentageBase::InstrumentedTime);
+        assert!(plain_report(&mut p).contains("total=5.0000ms (100.0000%)"));
+    }
+
+    #[test]
+    fn block_debug_shows_the_anchor_name() {
+        let _global = global();
+        let block = block_named!("debugged");
+        assert!(format!("{:?}", block).contains("anchor: \"debugged\""));
+        let guard = lock();
+        assert!(format!("{:?}", block).contains("anchor: \"<locked>\""));
+        drop(guard);
+    }
+
+    #[test]
+    fn hotness_ranks_are_consecutive_for_ties() {
+        let mut p = Profiler::new();
+        for (name, ms) in [("c", 3), ("a", 5), ("tie1", 2), ("tie2", 2), ("e", 1)] {
+            let id = p.get_anchor_id(name);
+            p.record_call(id, Duration::from_millis(ms));
+        }
+        assert_eq!(p.hotness_ranks()[1..], [Some(2), Some(1), Some(3), Some(4), Some(5)]);
+        let report = plain_report(&mut p);
+        for line in ["#1 a[1]", "#2 c[1]", "#3 tie1[1]", "#4 tie2[1]", "#5 e[1]"] {
+            assert!(report.contains(line), "{}", line);
+        }
+    }
+
+    #[test]
+    fn block_metric_sets_a_custom_metric() {
+        let _global = global();
+        let id = block_id!("metered");
+        block_metric!(id, "rows", 7.0);
+        assert_eq!(lock().anchors[id].custom_metrics["rows"], 7.0);
+    }
+
+    #[test]
+    fn saturated_counters_show_overflow() {
+        let mut p = profiler_spanning(Duration::from_millis(10));
+        let id = p.get_anchor_id("broken");
+        p.record_call(id, Duration::from_millis(1));
+        p.anchors[id].elapsed_inclusive = u64::MAX;
+        p.anchors[id].bytes = 1024;
+        let report = plain_report(&mut p);
+        let line = report.lines().find(|line| line.contains("broken[1]")).unwrap();
+        assert!(line.ends_with("total=OVERFLOW self=1.0000ms"), "{}", line);
+    }
+
+    #[test]
+    fn block_id_accumulates_bytes_for_the_throughput() {
+        let _global = global();
+        let id = block_id!("stream");
+        for _ in 0..10 {
+            lock().add_bytes(id, 100);
+        }
+        let mut p = lock();
+        p.record_call(id, Duration::from_millis(1));
+        // 1000 B over 1ms, in GiB per second.
+        assert!(plain_report(&mut p).contains("throughput=1000 B at 0.0009 GB/s ns/B=1000.0"));
+    }
+
+    #[test]
+    fn header_names_the_timer() {
+        let mut p = hot_profiler();
+        let timer = plain_report(&mut p).lines().nth(1).unwrap().to_string();
+        if cfg!(feature = "rdtsc") {
+            assert!(timer.starts_with("Timer: rdtsc (calibrated at "), "{}", timer);
+        } else {
+            assert_eq!(timer, "Timer: Instant (monotonic)");
+        }
+        p.set_wall_clock_mode(true);
+        assert_eq!(plain_report(&mut p).lines().nth(1), Some("Timer: Instant (monotonic)"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stderr_target_child() {
+        if !in_child() {
+            return;
+        }
+        let mut p = hot_profiler();
+        p.set_output_target(OutputTarget::Stderr);
+        p.print();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stderr_target_prints_to_stderr() {
+        let (stdout, stderr) = run_in_child("tests::stderr_target_child");
+        assert!(stdout.contains("test result: ok. 1 passed"), "{}", stdout);
+        assert!(!stdout.contains("--- PProf Results"), "{}", stdout);
+        assert!(stderr.contains("--- PProf Results"), "{}", stderr);
+    }
+
+    #[test]
+    fn leaf_ratio_is_the_share_of_self_time() {
+        let mut p = Profiler::new();
+        let [outer, inner] = ["outer", "inner"].map(|name| p.get_anchor_id(name));
+        timed(&mut p, outer, Duration::from_millis(1), |p| timed(p, inner, Duration::from_millis(9), |_| ()));
+        let report = plain_report(&mut p);
+        let ratio = |name: &str| -> f64 {
+            let line = report.lines().find(|line| line.contains(name)).unwrap();
+            line.split("leaf_ratio=").nth(1).unwrap()[..4].parse().unwrap()
+        };
+        assert!((ratio(" outer[1]") - 0.1).abs() < 0.02, "{}", report);
+        assert_eq!(ratio(" inner[1]"), 1.0);
+    }
+
+    #[test]
+    fn anchor_exists_once_registered() {
+        let _global = global();
+        assert!(!anchor_exists("registered"));
+        anchor_id("registered");
+        assert!(anchor_exists("registered"));
+        assert!(!anchor_exists("unregistered"));
+    }
+
+    #[test]
+    fn byte_sizes_pick_their_unit() {
+        let sizes = [(500, "500 B"), (2000, "1.95 KB"), (3 << 20, "3.00 MB"), (5 << 30, "5.00 GB")];
+        for (bytes, shown) in sizes {
+            assert_eq!(format_bytes(bytes), shown);
+        }
+        let mut p = Profiler::new();
+        let id = p.get_anchor_id("small");
+        p.record_call(id, Duration::from_millis(1));
+        p.add_bytes(id, 500);
+        assert_eq!(result_of(&p, "small").display_bytes(), "500 B");
+        assert!(plain_report(&mut p).contains("throughput=500 B at "));
+    }
+
+    #[test]
+    fn profilers_started_at_different_times_get_different_ids() {
+        let mut first = hot_profiler();
+        std::thread::sleep(Duration::from_millis(1));
+        let second = Profiler::new();
+        let id = first.generate_report_id();
+        assert_eq!(id.len(), 8);
+        assert_ne!(id, second.generate_report_id());
+        assert!(plain_report(&mut first).lines().next().unwrap().ends_with(&format!("[id={}] ---", id)));
+    }
+
+    #[cfg(feature = "profile")]
+    #[test]
+    fn profile_all_times_every_method_but_skipped_ones() {
+        struct Counter(u32);
+
+        #[profile_all]
+        impl Counter {
+            fn increment(&mut self) {
+                self.0 += 1;
+            }
+
+            fn decrement(&mut self) {
+                self.0 -= 1;
+            }
+
+            fn get(&self) -> u32 {
+                self.0
+            }
+
+            #[skip_profile]
+            fn reset(&mut self) {
+                self.0 = 0;
+            }
+        }
+
+        let _global = global();
+        let mut counter = Counter(0);
+        counter.increment();
+        counter.decrement();
+        counter.reset();
+        assert_eq!(counter.get(), 0);
+        let p = lock();
+        assert_eq!(p.anchor_count(), 3);
+        assert!(p.anchor_exists("pprof::tests::profile_all_times_every_method_but_skipped_ones::Counter::get"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn print_by_group_child() {
+        if !in_child() {
+            return;
+        }
+        let mut p = Profiler::new();
+        for name in ["read", "parse", "write", "flush"] {
+            let id = p.get_anchor_id(name);
+            p.record_call(id, Duration::from_millis(1));
+        }
+        for name in ["read", "write", "flush"] {
+            p.set_anchor_group(name, "IO");
+        }
+        p.set_color(false);
+        p.print_by_group();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn print_by_group_lists_a_group_under_one_header() {
+        let (stdout, _) = run_in_child("tests::print_by_group_child");
+        assert_eq!(stdout.matches("=== IO ===").count(), 1, "{}", stdout);
+        let io: Vec<&str> =
+            stdout.lines().skip_while(|l| *l != "=== IO ===").skip(1).take_while(|l| !l.starts_with("===")).collect();
+        assert_eq!(io.len(), 4, "{}", stdout);
+        for name in [" read[1]", " write[1]", " flush[1]"] {
+            assert!(io.iter().any(|line| line.contains(name)), "{}", stdout);
+        }
+        assert!(io[3].starts_with("  subtotal - self=3.0000ms"), "{}", stdout);
+        let other: Vec<&str> = stdout.lines().skip_while(|l| *l != "=== Other ===").collect();
+        assert!(other[1].contains(" parse[1]"), "{}", stdout);
+    }
+
+    #[cfg(feature = "timeline")]
+    #[test]
+    fn anchors_called_in_sequence_have_increasing_offsets() {
+        let mut p = Profiler::new();
+        let ids = ["first", "second", "third"].map(|name| p.get_anchor_id(name));
+        for id in ids {
+            timed(&mut p, id, Duration::from_millis(1), |_| ());
+        }
+        let report = plain_report(&mut p);
+        let offsets: Vec<f64> = ["first", "second", "third"]
+            .iter()
+            .map(|name| {
+                let line = report.lines().find(|line| line.contains(&format!(" {}[1]", name))).unwrap();
+                line.split(" @T+").nth(1).unwrap().split("ms").next().unwrap().parse().unwrap()
+            })
+            .collect();
+        assert!(offsets[0] < offsets[1] && offsets[1] < offsets[2], "{}", report);
+    }
+
+    #[test]
+    fn header_shows_the_crate_version() {
+        let report = plain_report(&mut hot_profiler());
+        let header = report.lines().next().unwrap();
+        assert!(header.starts_with(&format!("--- PProf Results [pprof v{}", env!("CARGO_PKG_VERSION"))), "{}", header);
+    }
+
+    #[test]
+    fn replayed_fixtures_print_the_same_report() {
+        let fixture = Profiler::import_json(
+            "{\"anchors\":[{\"name\":\"load\",\"calls\":2,\"bytes\":4096,\"self_ms\":3,\"total_ms\":4},\
+             {\"name\":\"decode\",\"calls\":8,\"bytes\":0,\"self_ms\":1,\"total_ms\":1}]}",
+        )
+        .unwrap();
+        let replayed = || {
+            let mut p = profiler_spanning(Duration::from_millis(10));
+            p.replay_as_synthetic_blocks(&fixture);
+            // Skips the header line, whose report ID depends on the time.
+            plain_report(&mut p).lines().skip(1).collect::<Vec<_>>().join("\n")
+        };
+        let report = replayed();
+        assert_eq!(report, replayed());
+        let line = |name: &str| report.lines().find(|line| line.contains(name)).unwrap();
+        assert!(line(" load[2]").contains(" - total=4.0000ms (40.0000%) self=3.0000ms (30.0000%)"), "{}", report);
+        assert!(line(" decode[8]").contains(" - total=1.0000ms (10.0000%) self=1.0000ms (10.0000%)"), "{}", report);
+    }
+
+    #[test]
+    fn threshold_callback_runs_for_slow_blocks() {
+        let _global = global();
+        let exceeded = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&exceeded);
+        lock().on_threshold_exceeded("slow", 1.0, move || flag.store(true, Ordering::Relaxed));
+        drop(block_named!("slow"));
+        assert!(!exceeded.load(Ordering::Relaxed));
+        {
+            let _b = block_named!("slow");
+            spin(Duration::from_millis(2));
+        }
+        assert!(exceeded.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn iter_by_self_time_yields_the_slowest_first() {
+        let mut p = Profiler::new();
+        for (name, ms) in [("b", 2), ("e", 5), ("a", 1), ("d", 4), ("c", 3)] {
+            let id = p.get_anchor_id(name);
+            p.record_call(id, Duration::from_millis(ms));
+        }
+        let top: Vec<String> = p.iter_by_self_time().take(3).map(|a| a.name).collect();
+        assert_eq!(top, ["e", "d", "c"]);
+        assert_eq!(p.iter_by_self_time().count(), 5);
+    }
+
+    #[test]
+    fn reset_anchor_leaves_the_others_alone() {
+        let _global = global();
+        let run_both = || {
+            drop(block_named!("reset"));
+            drop(block_named!("kept"));
+        };
+        run_both();
+        assert!(reset_anchor("reset"));
+        assert!(!reset_anchor("missing"));
+        run_both();
+        assert_called("reset", 1);
+        assert_called("kept", 2);
+    }
+
+    #[test]
+    fn throughput_summary_covers_every_anchor() {
+        let mut p = profiler_spanning(Duration::from_secs(1));
+        for name in ["read", "write"] {
+            let id = p.get_anchor_id(name);
+            p.record_call(id, Duration::from_millis(1));
+            p.add_bytes(id, 256 << 20);
+        }
+        assert_eq!(p.bytes_total(), 512 << 20);
+        assert!((p.bytes_throughput_gb_s() - 0.5).abs() < 1e-9);
+        let report = plain_report(&mut p);
+        assert!(report.contains("Total data: 512.00 MB at 0.5000 GB/s\n"), "{}", report);
+        assert!(report.contains(" bytes=512.00 MB throughput=0.5000 GB/s\n"), "{}", report);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn print_tree_recursion_child() {
+        if !in_child() {
+            return;
+        }
+        let mut p = Profiler::new();
+        let (main, parse) = (p.get_anchor_id("main"), p.get_anchor_id("parse_expr"));
+        let mut ids = vec![main];
+        ids.extend([parse; 10]);
+        call_chain(&mut p, &ids);
+        p.set_color(false);
+        p.print_tree();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn print_tree_collapses_recursion_into_one_line() {
+        let (stdout, _) = run_in_child("tests::print_tree_recursion_child");
+        let lines: Vec<&str> = stdout.lines().filter(|line| line.contains("parse_expr[")).collect();
+        assert_eq!(lines.len(), 1, "{}", stdout);
+        assert!(lines[0].starts_with("  parse_expr[10] × 10 - total="), "{}", stdout);
+    }
+
+    #[test]
+    fn with_returns_the_closure_value_and_propagates_panics() {
+        let _global = global();
+        assert_eq!(with("sum", || 2 + 3), 5);
+        let panicked = std::panic::catch_unwind(|| with("panics", || panic!("boom"))).unwrap_err();
+        assert_eq!(panicked.downcast_ref::<&str>(), Some(&"boom"));
+        assert_eq!(with_bytes("io", 100, || "done"), "done");
+        let p = lock();
+        assert!(p.assert_called("sum", 1).is_ok());
+        // The block ended while unwinding.
+        assert!(p.assert_called("panics", 1).is_ok());
+        assert_eq!(result_of(&p, "io").bytes, 100);
+    }
+
+    #[cfg(feature = "source-locations")]
+    #[test]
+    fn source_locations_show_where_the_block_is() {
+        let _global = global();
+        let line = line!() + 1;
+        drop(block_named!("located"));
+        let mut p = lock();
+        let location = format!(" located[1] [{}:{}]", file!(), line);
+        assert!(!plain_report(&mut p).contains(&location));
+        p.set_include_source_locations(true);
+        let report = plain_report(&mut p);
+        assert!(report.contains(&location), "{}", report);
+    }
+
+    #[test]
+    fn self_time_per_byte_is_shown_for_anchors_with_bytes() {
+        let mut p = profiler_spanning(Duration::from_secs(2));
+        let [io, cpu] = ["io", "cpu"].map(|name| p.get_anchor_id(name));
+        p.record_call(io, Duration::from_secs(1));
+        p.add_bytes(io, 1_000_000_000);
+        p.record_call(cpu, Duration::from_millis(1));
+        let report = plain_report(&mut p);
+        let line = |name: &str| report.lines().find(|line| line.contains(name)).unwrap();
+        assert!(line(" io[1]").contains(" ns/B=1.0"), "{}", report);
+        assert!(!line(" cpu[1]").contains("ns/B="), "{}", report);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sorted_ties_child() {
+        if !in_child() {
+            return;
+        }
+        let mut p = Profiler::new();
+        for (name, ms) in [("zeta", 1), ("alpha", 1), ("slowest", 2), ("mid", 1)] {
+            let id = p.get_anchor_id(name);
+            p.record_call(id, Duration::from_millis(ms));
+        }
+        p.set_color(false);
+        p.print_sorted_by(SortKey::SelfTimeDesc);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sorted_ties_are_in_name_order() {
+        let (stdout, _) = run_in_child("tests::sorted_ties_child");
+        let position = |name: &str| stdout.find(&format!(" {}[1]", name)).unwrap();
+        assert!(position("slowest") < position("alpha"), "{}", stdout);
+        assert!(position("alpha") < position("mid"), "{}", stdout);
+        assert!(position("mid") < position("zeta"), "{}", stdout);
+    }
+
+    #[test]
+    fn efficiency_score_measures_how_evenly_time_is_spread() {
+        let profiler_with = |self_times: &[u64]| {
+            let mut p = Profiler::new();
+            for (i, &ms) in self_times.iter().enumerate() {
+                let id = p.get_anchor_id(&format!("anchor{}", i));
+                p.record_call(id, Duration::from_millis(ms));
+            }
+            p
+        };
+        assert_eq!(profiler_with(&[10]).compute_efficiency_score(), 0.0);
+        assert!(profiler_with(&[1000, 0, 0, 0, 0]).compute_efficiency_score() < 1e-9);
+        assert!(profiler_with(&[1000, 1, 1]).compute_efficiency_score() < 0.01);
+        assert!((profiler_with(&[2; 5]).compute_efficiency_score() - 1.0).abs() < 1e-9);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn custom_header_and_footer_child() {
+        if !in_child() {
+            return;
+        }
+        let _global = global();
+        set_header("<<< pprof");
+        set_footer(">>> pprof");
+        drop(block_named!("framed"));
+        lock().set_color(false);
+        print();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn custom_header_and_footer_frame_the_report() {
+        let (stdout, _) = run_in_child("tests::custom_header_and_footer_child");
+        let report: Vec<&str> = stdout.lines().skip_while(|l| !l.ends_with("<<< pprof")).collect();
+        assert!(report[1].starts_with("Timer: "), "{}", stdout);
+        assert!(report.contains(&">>> pprof"), "{}", stdout);
+        assert!(!stdout.contains("--- PProf Results"), "{}", stdout);
+    }
+
+    #[cfg(feature = "source-locations")]
+    #[test]
+    fn anchors_used_in_several_places_are_warned_about() {
+        let _global = global();
+        drop(block!("parse"));
+        drop(block!("parse"));
+        drop(block!("tokenize"));
+        let report = plain_report(&mut lock());
+        assert!(report.contains("[parse]' has 2 source locations — statistics are merged"), "{}", report);
+        assert!(!report.contains("[tokenize]' has"), "{}", report);
+    }
+
+    #[test]
+    fn long_profiles_show_a_human_readable_total() {
+        assert_eq!(format_duration_human(125_000.0), "2m 5.000s");
+        assert_eq!(format_duration_human(90_061_500.0), "1d 1h 1m 1.500s");
+        assert_eq!(format_duration_human(3_600_000.0), "1h 0m 0.000s");
+        let report = plain_report(&mut profiler_spanning(Duration::from_secs(125)));
+        assert!(report.contains("Total time: 2m 5.000s (125000.0000ms)\n"), "{}", report);
+        let report = plain_report(&mut profiler_spanning(Duration::from_secs(59)));
+        assert!(report.contains("Total time: 59000.0000ms\n"), "{}", report);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn multi_key_sort_child() {
+        if !in_child() {
+            return;
+        }
+        let mut p = Profiler::new();
+        for i in (0..10).rev() {
+            let id = p.get_anchor_id(&format!("anchor{}", i));
+            p.record_call(id, Duration::from_millis(1));
+        }
+        p.set_color(false);
+        p.print_sorted_by_multi(&[SortKey::SelfTimeDesc, SortKey::NameAsc]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn multi_key_sort_breaks_ties_with_the_next_key() {
+        let (stdout, _) = run_in_child("tests::multi_key_sort_child");
+        let order: Vec<&str> = stdout
+            .lines()
+            .filter_map(|line| line.split(" anchor").nth(1))
+            .filter(|rest| rest.contains("[1]"))
+            .map(|rest| &rest[..1])
+            .collect();
+        assert_eq!(order, ["0", "1", "2", "3", "4", "5", "6", "7", "8", "9"], "{}", stdout);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn print_function_only_child() {
+        if !in_child() {
+            return;
+        }
+        let mut p = Profiler::new();
+        for name in ["my_crate::parser::json::parse", "my_crate::parser::xml::parse", "my_crate::run"] {
+            let id = p.get_anchor_id(name);
+            p.record_call(id, Duration::from_millis(1));
+        }
+        p.set_color(false);
+        p.print_function_only();
+        // Lookups still go by the full name.
+        assert_eq!(p.get_anchor_id("my_crate::parser::json::parse"), 1);
+        assert!(p.anchor_exists("my_crate::run"));
+        assert!(!p.anchor_exists("run"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn print_function_only_strips_module_paths() {
+        let (stdout, _) = run_in_child("tests::print_function_only_child");
+        assert!(stdout.contains("test result: ok. 1 passed"), "{}", stdout);
+        for name in [" parse[1]", " parse (2)[1]", " run[1]"] {
+            assert!(stdout.contains(name), "{}", stdout);
+        }
+        assert!(!stdout.contains("my_crate::"), "{}", stdout);
+    }
+
+    #[test]
+    fn both_clock_sources_time_blocks() {
+        let mut p = Profiler::new();
+        for source in [ClockSource::Instant, ClockSource::Rdtsc, ClockSource::Instant] {
+            p.set_clock_source(source);
+            let expected = if cfg!(feature = "rdtsc") { source } else { ClockSource::Instant };
+            assert_eq!(p.clock_source(), expected);
+            let id = p.get_anchor_id("timed");
+            let block = start(&mut p, id);
+            spin(Duration::from_micros(200));
+            end(&mut p, block);
+            let ms = result_of(&p, "timed").exclusive_ms;
+            // Calibrating the TSC isn't exact, so this leaves some slack.
+            assert!((0.1..100.0).contains(&ms), "{:?}: {}ms", source, ms);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn print_by_depth_child() {
+        if !in_child() {
+            return;
+        }
+        let mut p = Profiler::new();
+        let [fast, main, mid, leaf] = ["fast", "main", "mid", "leaf"].map(|name| p.get_anchor_id(name));
+        timed(&mut p, fast, Duration::from_millis(1), |_| ());
+        timed(&mut p, main, Duration::from_millis(3), |p| {
+            timed(p, mid, Duration::from_millis(1), |p| timed(p, leaf, Duration::from_millis(1), |_| ()))
+        });
+        p.set_color(false);
+        p.print_by_depth();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn print_by_depth_groups_anchors_by_level() {
+        let (stdout, _) = run_in_child("tests::print_by_depth_child");
+        let shown: Vec<&str> = stdout
+            .lines()
+            .filter_map(|line| {
+                if line.starts_with("--- Depth ") {
+                    Some(line)
+                } else {
+                    ["fast", "main", "mid", "leaf"].into_iter().find(|name| line.contains(&format!(" {}[1]", name)))
+                }
+            })
+            .collect();
+        let expected = ["--- Depth 1 ---", "main", "fast", "--- Depth 2 ---", "mid", "--- Depth 3 ---", "leaf"];
+        assert_eq!(shown, expected, "{}", stdout);
+    }
+
+    #[cfg(all(feature = "addr2line", target_os = "linux"))]
+    #[test]
+    fn hex_anchors_are_named_after_the_function_at_that_address() {
+        #[inline(never)]
+        fn symbol_target() -> u32 {
+            std::hint::black_box(42)
+        }
+
+        // The test binary is position independent, so its addresses are
+        // offsets from where it was loaded.
+        let exe = std::env::current_exe().unwrap();
+        let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+        let base = maps
+            .lines()
+            .find(|line| line.ends_with(exe.to_str().unwrap()))
+            .and_then(|line| line.split('-').next())
+            .map(|start| usize::from_str_radix(start, 16).unwrap())
+            .unwrap();
+        let address = format!("{:#x}", symbol_target as fn() -> u32 as usize - base);
+
+        let mut p = Profiler::new();
+        for name in [address.as_str(), "0x0", "not_an_address"] {
+            let id = p.get_anchor_id(name);
+            p.record_call(id, Duration::from_millis(1));
+        }
+        p.annotate_with_symbol_names(&exe).unwrap();
+        let names: Vec<String> = p.results().anchors.into_iter().map(|a| a.name).collect();
+        let (function, location) = names[0].split_once(" (").unwrap();
+        assert!(function.ends_with("::symbol_target"), "{:?}", names);
+        assert!(location.contains("src/lib.rs:"), "{:?}", names);
+        assert_eq!(names[1..], ["0x0", "not_an_address"]);
+    }
+
+    #[test]
+    fn only_anchors_above_the_percentile_are_slow() {
+        let mut p = Profiler::new();
+        for i in 1..=10 {
+            let id = p.get_anchor_id(&format!("step{}", i));
+            p.record_call(id, Duration::from_millis(i));
+        }
+        p.set_slow_percentile(Some(90.0));
+        let report = plain_report(&mut p);
+        let slow: Vec<&str> = report.lines().filter(|line| line.contains(" [SLOW]")).collect();
+        assert_eq!(slow.len(), 1, "{}", report);
+        assert!(slow[0].contains(" step10[1] [SLOW]"), "{}", report);
+        p.set_slow_percentile(None);
+        assert!(!plain_report(&mut p).contains("[SLOW]"));
+    }
+
+    #[test]
+    fn unmeasurably_fast_throughput_is_not_infinite() {
+        let mut p = profiler_spanning(Duration::from_millis(1));
+        let [fast, empty] = ["fast", "empty"].map(|name| p.get_anchor_id(name));
+        p.record_call(fast, Duration::ZERO);
+        p.add_bytes(fast, 1024);
+        p.record_call(empty, Duration::ZERO);
+        let report = plain_report(&mut p);
+        assert!(report.contains(" throughput=1.00 KB at >1 TB/s (timer resolution insufficient)"), "{}", report);
+        // Without bytes, there is nothing to show.
+        assert!(!report.contains(" empty[1]"), "{}", report);
+        assert!(!report.contains("inf") && !report.contains("NaN"), "{}", report);
+    }
+
+    #[test]
+    fn walk_call_tree_visits_depth_first() {
+        let mut p = Profiler::new();
+        let [main, parse, lex, emit] = ["main", "parse", "lex", "emit"].map(|name| p.get_anchor_id(name));
+        timed(&mut p, main, Duration::from_micros(50), |p| {
+            timed(p, parse, Duration::from_micros(50), |p| timed(p, lex, Duration::from_micros(50), |_| ()));
+            timed(p, emit, Duration::from_micros(50), |_| ());
+        });
+        let mut visited = Vec::new();
+        p.walk_call_tree(|depth, result| visited.push((depth, result.name.clone())));
+        let expected = [(0, "main"), (1, "parse"), (2, "lex"), (1, "emit")].map(|(depth, name)| (depth, name.to_string()));
+        assert_eq!(visited, expected);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn print_inside_blocks_child() {
+        if !in_child() {
+            return;
+        }
+        let mut p = Profiler::new();
+        p.set_color(false);
+        let [outer, inner] = ["outer", "inner"].map(|name| p.get_anchor_id(name));
+        let outer_block = start(&mut p, outer);
+        let inner_block = start(&mut p, inner);
+        p.print();
+        end(&mut p, inner_block);
+        end(&mut p, outer_block);
+        p.print();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn prints_inside_a_block_warn_about_it() {
+        let (stdout, _) = run_in_child("tests::print_inside_blocks_child");
+        assert!(stdout.contains("test result: ok. 1 passed"), "{}", stdout);
+        assert_eq!(stdout.matches("\nActive blocks at print time: 2 (statistics are partial)\n").count(), 1, "{}", stdout);
+        assert_eq!(stdout.matches("--- PProf Results").count(), 2, "{}", stdout);
+    }
+
+    #[test]
+    fn name_map_renames_anchors_and_numbers_conflicts() {
+        let mut p = Profiler::new();
+        for name in ["fn_0x1a", "fn_0x2b", "fn_0x3c", "parse", "a", "b"] {
+            let id = p.get_anchor_id(name);
+            p.record_call(id, Duration::from_millis(1));
+        }
+        let map: HashMap<String, String> =
+            [("fn_0x1a", "decode"), ("fn_0x2b", "decode"), ("fn_0x3c", "parse"), ("a", "b"), ("b", "a")]
+                .into_iter()
+                .map(|(from, to)| (from.to_string(), to.to_string()))
+                .collect();
+        p.apply_name_map(&map);
+        let names: Vec<String> = p.results().anchors.into_iter().map(|a| a.name).collect();
+        assert_eq!(names, ["decode", "decode (2)", "parse (2)", "parse", "b", "a"]);
+        // Lookups go by the new names.
+        assert_eq!(p.get_anchor_id("decode (2)"), 2);
+        assert!(!p.anchor_exists("fn_0x1a"));
+    }
+
+    #[cfg(feature = "atomic-stats")]
+    #[test]
+    fn read_anchor_atomic_reads_the_published_totals() {
+        let _global = global();
+        for _ in 0..3 {
+            let _block = block_named!("atomic");
+            spin(Duration::from_micros(10));
+        }
+        let result = Profiler::read_anchor_atomic("atomic").unwrap();
+        assert_eq!(result.calls, 3);
+        assert!(result.inclusive_ms > 0.0);
+        assert!(Profiler::read_anchor_atomic("never").is_none());
+    }
+
+    #[cfg(feature = "atomic-stats")]
+    #[test]
+    fn read_anchor_atomic_does_not_wait_for_the_profiler_lock() {
+        let _global = global();
+        drop(block_named!("locked"));
+        let guard = lock();
+        let (tx, rx) = std::sync::mpsc::channel();
+        thread::spawn(move || tx.send(Profiler::read_anchor_atomic("locked")).unwrap());
+        let result = rx.recv_timeout(Duration::from_secs(10)).expect("the read waited for PROFILER");
+        drop(guard);
+        assert_eq!(result.unwrap().calls, 1);
+    }
+
+    #[cfg(feature = "atomic-stats")]
+    #[test]
+    fn only_the_global_profiler_publishes_atomic_stats() {
+        let _global = global();
+        let mut fork = lock().fork();
+        let id = fork.get_anchor_id("forked");
+        fork.record_call(id, Duration::from_millis(1));
+        assert!(Profiler::read_anchor_atomic("forked").is_none());
+    }
 }